@@ -0,0 +1,281 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+
+use crate::domain::{Ledger, LedgerError};
+
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum CliError {
+    #[error("Input file not provided")]
+    MissingInput,
+    #[error("Unknown argument `{0}`")]
+    UnknownArgument(String),
+    #[error("Missing value for `{0}`")]
+    MissingValue(String),
+    #[error("Invalid stream `{0}`, expected `stdout` or `stderr`")]
+    InvalidStream(String),
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl FromStr for OutputStream {
+    type Err = CliError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(OutputStream::Stdout),
+            "stderr" => Ok(OutputStream::Stderr),
+            _ => Err(CliError::InvalidStream(s.to_string())),
+        }
+    }
+}
+
+/// Which stream the balance dump and the logs are written to
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct OutputRouting {
+    pub dump: OutputStream,
+    pub logs: OutputStream,
+}
+
+impl Default for OutputRouting {
+    fn default() -> Self {
+        Self {
+            dump: OutputStream::Stdout,
+            logs: OutputStream::Stderr,
+        }
+    }
+}
+
+impl OutputRouting {
+    /// Writes the dump (or the aggregate if `stats_only`) of `ledger` to whichever of
+    /// `stdout`/`stderr` is configured
+    pub fn write_dump<O, E>(
+        &self,
+        ledger: &Ledger,
+        stats_only: bool,
+        stdout: &mut O,
+        stderr: &mut E,
+    ) -> Result<(), LedgerError>
+    where
+        O: Write,
+        E: Write,
+    {
+        match self.dump {
+            OutputStream::Stdout => write_output(ledger, stats_only, stdout),
+            OutputStream::Stderr => write_output(ledger, stats_only, stderr),
+        }
+    }
+}
+
+fn write_output<W: Write>(ledger: &Ledger, stats_only: bool, w: &mut W) -> Result<(), LedgerError> {
+    if stats_only {
+        ledger.write_aggregate(w)
+    } else {
+        ledger.dump_to_writer(w)
+    }
+}
+
+/// Arguments of the executable:
+/// `<input>... [--output <path>] [--stats-only] [--dump-to <stream>] [--logs-to <stream>]`
+#[derive(Debug, PartialEq, Clone)]
+pub struct CliArgs {
+    /// Processed in order into the same ledger
+    pub inputs: Vec<String>,
+    /// File the dump is written to instead of the `routing.dump` stream
+    pub output: Option<String>,
+    /// Write the totals over all clients instead of a row per client
+    pub stats_only: bool,
+    pub routing: OutputRouting,
+}
+
+impl CliArgs {
+    /// Parses the arguments, without the program name
+    pub fn parse<I>(args: I) -> Result<CliArgs, CliError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut inputs = Vec::new();
+        let mut output = None;
+        let mut stats_only = false;
+        let mut routing = OutputRouting::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--dump-to" | "--logs-to" => {
+                    let stream = args
+                        .next()
+                        .ok_or_else(|| CliError::MissingValue(arg.clone()))?
+                        .parse()?;
+                    if arg == "--dump-to" {
+                        routing.dump = stream;
+                    } else {
+                        routing.logs = stream;
+                    }
+                }
+                "--stats-only" => stats_only = true,
+                "--output" if output.is_none() => {
+                    output = Some(args.next().ok_or(CliError::MissingValue(arg))?);
+                }
+                _ if arg.starts_with("--") => return Err(CliError::UnknownArgument(arg)),
+                _ => inputs.push(arg),
+            }
+        }
+        if inputs.is_empty() {
+            return Err(CliError::MissingInput);
+        }
+        Ok(CliArgs {
+            inputs,
+            output,
+            stats_only,
+            routing,
+        })
+    }
+
+    /// Writes the dump (or the aggregate if `stats_only`) of `ledger` to the `output`
+    /// file, creating or truncating it, or through the `routing` when there's none
+    pub fn write_dump<O, E>(
+        &self,
+        ledger: &Ledger,
+        stdout: &mut O,
+        stderr: &mut E,
+    ) -> Result<(), LedgerError>
+    where
+        O: Write,
+        E: Write,
+    {
+        match &self.output {
+            Some(path) => {
+                let mut file = BufWriter::new(File::create(path)?);
+                write_output(ledger, self.stats_only, &mut file)?;
+                Ok(file.flush()?)
+            }
+            None => self
+                .routing
+                .write_dump(ledger, self.stats_only, stdout, stderr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::domain::Transaction;
+    use rust_decimal_macros::dec;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_keeps_dump_on_stdout_and_logs_on_stderr_by_default() {
+        let parsed = CliArgs::parse(args(&["transactions.csv"])).unwrap();
+
+        assert_eq!(parsed.inputs, ["transactions.csv"]);
+        assert_eq!(parsed.output, None);
+        assert!(!parsed.stats_only);
+        assert_eq!(parsed.routing.dump, OutputStream::Stdout);
+        assert_eq!(parsed.routing.logs, OutputStream::Stderr);
+        assert_eq!(CliArgs::parse(args(&[])), Err(CliError::MissingInput));
+    }
+
+    #[test]
+    fn test_parse_routes_dump_and_logs_independently() {
+        let parsed = CliArgs::parse(args(&[
+            "--dump-to",
+            "stderr",
+            "transactions.csv",
+            "--logs-to",
+            "stdout",
+        ]))
+        .unwrap();
+
+        assert_eq!(
+            parsed.routing,
+            OutputRouting {
+                dump: OutputStream::Stderr,
+                logs: OutputStream::Stdout,
+            }
+        );
+        assert_eq!(
+            CliArgs::parse(args(&["transactions.csv", "--dump-to", "file"])),
+            Err(CliError::InvalidStream("file".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_takes_every_positional_as_input_and_output_from_flag() {
+        let parsed = CliArgs::parse(args(&[
+            "day1.csv",
+            "--output",
+            "accounts.csv",
+            "day2.csv",
+            "day3.csv",
+        ]))
+        .unwrap();
+
+        assert_eq!(parsed.inputs, ["day1.csv", "day2.csv", "day3.csv"]);
+        assert_eq!(parsed.output.as_deref(), Some("accounts.csv"));
+        assert_eq!(
+            CliArgs::parse(args(&["transactions.csv", "--output"])),
+            Err(CliError::MissingValue("--output".to_string()))
+        );
+        assert_eq!(
+            CliArgs::parse(args(&["--output", "a.csv", "--output", "b.csv"])),
+            Err(CliError::UnknownArgument("--output".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_stats_only_writes_the_aggregate() {
+        let ledger = Ledger::new();
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(5),
+            })
+            .unwrap();
+        let parsed = CliArgs::parse(args(&["transactions.csv", "--stats-only"])).unwrap();
+        assert!(parsed.stats_only);
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        parsed
+            .write_dump(&ledger, &mut stdout, &mut stderr)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(stdout).unwrap(),
+            "clients, available, held, total, locked\n1, 5, 0, 5, 0\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_lands_on_the_configured_stream() {
+        let ledger = Ledger::new();
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(5),
+            })
+            .unwrap();
+        let routing = OutputRouting {
+            dump: OutputStream::Stderr,
+            ..Default::default()
+        };
+
+        let (mut stdout, mut stderr) = (Vec::new(), Vec::new());
+        routing
+            .write_dump(&ledger, false, &mut stdout, &mut stderr)
+            .unwrap();
+
+        assert!(stdout.is_empty());
+        assert_eq!(
+            String::from_utf8(stderr).unwrap(),
+            "client, available, held, total, locked\n1, 5, 0, 5, false\n"
+        );
+    }
+}
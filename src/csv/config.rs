@@ -0,0 +1,59 @@
+use std::sync::Arc;
+
+use crate::domain::ClientId;
+
+/// Options applied while parsing records into `Transaction`s.
+/// `StreamConfig::default()` keeps the behavior described in the README.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct StreamConfig {
+    /// Reject records with a `timestamp` (unix seconds) later than this "as-of" time.
+    /// Records without a timestamp are always accepted.
+    pub as_of: Option<u64>,
+    /// Reject records with more columns than the header instead of ignoring the extra ones.
+    pub strict_columns: bool,
+    /// Namespace the client ids of this input with `namespaced_client_id`, so several
+    /// tenants' inputs can be processed into one `Ledger` without mixing their clients.
+    pub client_namespace: Option<u16>,
+    /// Treat a deposit with a blank amount as a zero deposit instead of rejecting it.
+    pub blank_amount_as_zero: bool,
+    /// Drop a final record that can't be parsed and isn't followed by a newline, e.g.
+    /// cut off by an interrupted write, instead of reporting it as truncated.
+    pub ignore_truncated_final_record: bool,
+    /// Round amounts with more than `MAX_DECIMAL_PLACES` decimal places to that many, with
+    /// the midpoint going to the even neighbour (banker's rounding), instead of rejecting them.
+    pub round_excess_decimal_places: bool,
+    /// Rewrites every client id (after namespacing), e.g. for anonymization.
+    /// Ids mapped to the same value share a wallet.
+    pub client_id_map: Option<ClientIdMap>,
+    /// Field delimiter, e.g. `b'\t'` or `b';'`. A comma if `None`.
+    pub delimiter: Option<u8>,
+}
+
+/// Function rewriting client ids, compared by identity
+#[derive(Clone)]
+pub struct ClientIdMap(pub Arc<dyn Fn(ClientId) -> ClientId + Send + Sync>);
+
+impl ClientIdMap {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(ClientId) -> ClientId + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    pub fn apply(&self, client: ClientId) -> ClientId {
+        (self.0)(client)
+    }
+}
+
+impl std::fmt::Debug for ClientIdMap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ClientIdMap(..)")
+    }
+}
+
+impl PartialEq for ClientIdMap {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
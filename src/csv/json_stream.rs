@@ -0,0 +1,126 @@
+use tokio::io::{AsyncBufReadExt, AsyncRead, BufReader};
+
+use super::{TransactionRecord, TransactionRecordError};
+use crate::domain::Transaction;
+
+/// Reads newline-delimited JSON, one object per line with the same fields as the CSV
+/// columns, e.g. `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`.
+/// Amounts should be strings, JSON numbers go through a float. Blank lines are skipped.
+/// A failure reading `reader` is yielded as a `TransactionRecordError::IoError` and ends
+/// the stream.
+pub async fn create_transaction_stream_json<R>(
+    reader: R,
+) -> impl futures::Stream<Item = Result<Transaction, TransactionRecordError>>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let lines = BufReader::new(reader).lines();
+    // boxed so the stream is `Unpin`, like the CSV one
+    Box::pin(futures::stream::unfold(Some(lines), |lines| async move {
+        let mut lines = lines?;
+        loop {
+            let line = match lines.next_line().await {
+                Ok(Some(line)) => line,
+                Ok(None) => return None,
+                Err(e) => return Some((Err(e.into()), None)),
+            };
+            if line.trim().is_empty() {
+                continue;
+            }
+            let transaction = serde_json::from_str::<TransactionRecord>(&line)
+                .map_err(TransactionRecordError::from)
+                .and_then(Transaction::try_from);
+            return Some((transaction, Some(lines)));
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use rust_decimal_macros::dec;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_json_stream_parses_transactions_with_and_without_amount() {
+        let test_data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "10.5"}
+{"type": "withdrawal", "client": 1, "tx": 2, "amount": "2"}
+
+{"type": "dispute", "client": 1, "tx": 1}
+"#;
+        let transactions: Vec<_> = create_transaction_stream_json(test_data.as_bytes())
+            .await
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(
+            transactions,
+            [
+                Transaction::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: dec!(10.5),
+                },
+                Transaction::Withdrawal {
+                    client: 1,
+                    tx: 2,
+                    amount: dec!(2),
+                },
+                Transaction::Dispute { client: 1, tx: 1 },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_json_stream_reports_malformed_line_and_continues() {
+        let test_data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"
+{"type": "withdrawal", "client": 1, "tx": 2}
+{"type": "resolve", "client": 1, "tx": 1}
+"#;
+        let mut transaction_stream = create_transaction_stream_json(test_data.as_bytes()).await;
+
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::JsonError(_))
+        ));
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::MissingAmountError)
+        ));
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Resolve { client: 1, tx: 1 }
+        );
+        assert!(transaction_stream.next().await.is_none());
+    }
+
+    /// Fails every read, to simulate a disk error
+    struct FailingReader;
+
+    impl AsyncRead for FailingReader {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Ready(Err(std::io::Error::other("disk error")))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_stream_reports_read_failure_as_io_error_and_ends() {
+        let test_data = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": "1"}
+"#;
+        let reader = tokio::io::AsyncReadExt::chain(test_data.as_bytes(), FailingReader);
+        let mut transaction_stream = create_transaction_stream_json(reader).await;
+
+        assert!(transaction_stream.next().await.unwrap().is_ok());
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::IoError(_))
+        ));
+        assert!(transaction_stream.next().await.is_none());
+    }
+}
@@ -1,5 +1,12 @@
+mod config;
+pub mod json_stream;
 pub mod transaction_record;
 pub mod transaction_stream;
 
+pub use config::{ClientIdMap, StreamConfig};
+pub use json_stream::create_transaction_stream_json;
 pub use transaction_record::*;
-pub use transaction_stream::create_transaction_stream;
+pub use transaction_stream::{
+    create_numbered_transaction_stream, create_transaction_stream,
+    create_transaction_stream_with_config, create_transaction_stream_with_delimiter,
+};
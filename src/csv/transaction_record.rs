@@ -1,56 +1,363 @@
-use crate::domain::ClientId;
+use super::StreamConfig;
+use crate::domain::namespaced_client_id;
 use crate::domain::Decimal;
+use crate::domain::InputClientId;
 use crate::domain::Transaction;
 use crate::domain::TransactionId;
+use crate::domain::TransactionType;
+use rust_decimal::RoundingStrategy;
 
 #[derive(thiserror::Error, Debug)]
 pub enum TransactionRecordError {
     #[error("Missing amount field")]
     MissingAmountError,
+    #[error("Transaction dated in the future: {0}")]
+    FutureDated(u64),
+    #[error("Record has more columns than the header: {0}")]
+    UnexpectedColumns(usize),
+    #[error("Amount must be positive: {0}")]
+    NonPositiveAmount(Decimal),
+    #[error("Amount has more than {MAX_DECIMAL_PLACES} decimal places: {0}")]
+    TooManyDecimalPlaces(Decimal),
+    #[error("Client id 0 is reserved")]
+    ReservedClientId,
+    #[error("Final record on line {0} is truncated")]
+    TruncatedRecord(u64),
     #[error("csv error")]
     CsvError(#[from] csv_async::Error),
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+    /// Reading the input failed, outside of the CSV reader which reports it as a `CsvError`
+    #[error("io error: {0}")]
+    IoError(#[from] std::io::Error),
 }
 
-#[derive(serde::Deserialize, Debug)]
+/// Amounts are expected with at most this many decimal places
+pub const MAX_DECIMAL_PLACES: u32 = 4;
+
+/// A single problem found in a record by `validate_record`
+#[derive(thiserror::Error, Debug, PartialEq)]
+pub enum RowError {
+    #[error("Missing amount field")]
+    MissingAmount,
+    #[error("Amount must be positive: {0}")]
+    NonPositiveAmount(Decimal),
+    #[error("Amount has more than {MAX_DECIMAL_PLACES} decimal places: {0}")]
+    TooManyDecimalPlaces(Decimal),
+    #[error("Client id 0 is reserved")]
+    ReservedClientId,
+    /// Any other conversion error, which the checks of `validate_record` don't cover
+    #[error("{0}")]
+    Invalid(String),
+}
+
+impl From<TransactionRecordError> for RowError {
+    fn from(e: TransactionRecordError) -> Self {
+        match e {
+            TransactionRecordError::MissingAmountError => RowError::MissingAmount,
+            TransactionRecordError::NonPositiveAmount(amount) => {
+                RowError::NonPositiveAmount(amount)
+            }
+            TransactionRecordError::TooManyDecimalPlaces(amount) => {
+                RowError::TooManyDecimalPlaces(amount)
+            }
+            TransactionRecordError::ReservedClientId => RowError::ReservedClientId,
+            e => RowError::Invalid(e.to_string()),
+        }
+    }
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
 pub struct TransactionRecord {
     r#type: TransactionType,
-    client: ClientId,
+    client: InputClientId,
     tx: TransactionId,
     amount: Option<Decimal>,
+    /// Optional column, unix timestamp in seconds
+    #[serde(default)]
+    timestamp: Option<u64>,
+}
+
+impl TransactionRecord {
+    /// Converts the record applying the checks enabled in `config`
+    pub fn into_transaction(
+        self,
+        config: &StreamConfig,
+    ) -> Result<Transaction, TransactionRecordError> {
+        if let (Some(as_of), Some(timestamp)) = (config.as_of, self.timestamp) {
+            if timestamp > as_of {
+                return Err(TransactionRecordError::FutureDated(timestamp));
+            }
+        }
+        self.convert(config)
+    }
+
+    fn convert(self, config: &StreamConfig) -> Result<Transaction, TransactionRecordError> {
+        if self.client == 0 {
+            return Err(TransactionRecordError::ReservedClientId);
+        }
+        let tx = self.tx;
+        let mut client =
+            namespaced_client_id(config.client_namespace.unwrap_or_default(), self.client);
+        if let Some(client_id_map) = &config.client_id_map {
+            client = client_id_map.apply(client);
+        }
+        match self.r#type {
+            TransactionType::Deposit => {
+                // a blank amount read as zero is the only accepted non-positive deposit
+                let amount = match self.amount {
+                    None if config.blank_amount_as_zero => Decimal::ZERO,
+                    amount => limit_decimal_places(positive_amount(amount)?, config)?,
+                };
+                Ok(Transaction::Deposit { client, tx, amount })
+            }
+            TransactionType::Withdrawal => {
+                let amount = limit_decimal_places(positive_amount(self.amount)?, config)?;
+                Ok(Transaction::Withdrawal { client, tx, amount })
+            }
+            // Only the ledger knows whether the amount is used (`EngineConfig::partial_disputes`)
+            // so it's passed on as is, and validated against the deposit only when it is.
+            TransactionType::Dispute => match self.amount {
+                Some(amount) => Ok(Transaction::PartialDispute { client, tx, amount }),
+                None => Ok(Transaction::Dispute { client, tx }),
+            },
+            TransactionType::Resolve => Ok(Transaction::Resolve { client, tx }),
+            TransactionType::Chargeback => Ok(Transaction::Chargeback { client, tx }),
+            TransactionType::Freeze => Ok(Transaction::Freeze { client, tx }),
+            TransactionType::Unfreeze => Ok(Transaction::Unfreeze { client, tx }),
+        }
+    }
 }
 
-#[derive(serde::Deserialize, Debug, PartialEq, Clone)]
-#[serde(rename_all = "lowercase")]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+fn positive_amount(amount: Option<Decimal>) -> Result<Decimal, TransactionRecordError> {
+    match amount {
+        Some(amount) if amount <= Decimal::ZERO => {
+            Err(TransactionRecordError::NonPositiveAmount(amount))
+        }
+        amount => amount.ok_or(TransactionRecordError::MissingAmountError),
+    }
+}
+
+fn limit_decimal_places(
+    amount: Decimal,
+    config: &StreamConfig,
+) -> Result<Decimal, TransactionRecordError> {
+    if amount.normalize().scale() <= MAX_DECIMAL_PLACES {
+        Ok(amount)
+    } else if config.round_excess_decimal_places {
+        Ok(
+            amount
+                .round_dp_with_strategy(MAX_DECIMAL_PLACES, RoundingStrategy::MidpointNearestEven),
+        )
+    } else {
+        Err(TransactionRecordError::TooManyDecimalPlaces(amount))
+    }
 }
 
 impl TryFrom<TransactionRecord> for Transaction {
     type Error = TransactionRecordError;
 
     fn try_from(value: TransactionRecord) -> Result<Self, Self::Error> {
-        let tx = value.tx;
-        let client = value.client;
-        match value.r#type {
-            TransactionType::Deposit => {
-                let amount = value
-                    .amount
-                    .ok_or(TransactionRecordError::MissingAmountError)?;
-                Ok(Self::Deposit { client, tx, amount })
+        value.convert(&StreamConfig::default())
+    }
+}
+
+/// Checks `record` against every rule enabled in `config`, returning all of its problems
+/// at once instead of stopping at the first one like `into_transaction` does.
+pub fn validate_record(
+    record: &TransactionRecord,
+    config: &StreamConfig,
+) -> Result<Transaction, Vec<RowError>> {
+    let mut errors = Vec::new();
+    if record.client == 0 {
+        errors.push(RowError::ReservedClientId);
+    }
+    match (&record.r#type, record.amount) {
+        (TransactionType::Deposit, None) if config.blank_amount_as_zero => {}
+        (TransactionType::Deposit | TransactionType::Withdrawal, amount) => {
+            if let Err(e) = positive_amount(amount) {
+                errors.push(e.into());
             }
-            TransactionType::Withdrawal => {
-                let amount = value
-                    .amount
-                    .ok_or(TransactionRecordError::MissingAmountError)?;
-                Ok(Self::Withdrawal { client, tx, amount })
-            }
-            TransactionType::Dispute => Ok(Self::Dispute { client, tx }),
-            TransactionType::Resolve => Ok(Self::Resolve { client, tx }),
-            TransactionType::Chargeback => Ok(Self::Chargeback { client, tx }),
+            if let Some(Err(e)) = amount.map(|amount| limit_decimal_places(amount, config)) {
+                errors.push(e.into());
+            }
+        }
+        // the ledger validates a dispute amount if it uses it, the other types ignore it
+        _ => {}
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    record
+        .clone()
+        .into_transaction(config)
+        .map_err(|e| vec![e.into()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    fn record(
+        r#type: TransactionType,
+        client: InputClientId,
+        amount: Option<Decimal>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            r#type,
+            client,
+            tx: 1,
+            amount,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_validate_record_reports_every_problem_of_a_row() {
+        let config = StreamConfig::default();
+        let result = validate_record(
+            &record(TransactionType::Withdrawal, 0, Some(dec!(-1.00001))),
+            &config,
+        );
+
+        assert_eq!(
+            result,
+            Err(vec![
+                RowError::ReservedClientId,
+                RowError::NonPositiveAmount(dec!(-1.00001)),
+                RowError::TooManyDecimalPlaces(dec!(-1.00001)),
+            ])
+        );
+        assert_eq!(
+            validate_record(&record(TransactionType::Deposit, 0, None), &config),
+            Err(vec![RowError::ReservedClientId, RowError::MissingAmount])
+        );
+    }
+
+    #[test]
+    fn test_validate_record_accepts_what_the_stream_config_accepts() {
+        let config = StreamConfig {
+            blank_amount_as_zero: true,
+            round_excess_decimal_places: true,
+            ..Default::default()
+        };
+        let blank_deposit = record(TransactionType::Deposit, 1, None);
+        let long_withdrawal = record(TransactionType::Withdrawal, 1, Some(dec!(1.00001)));
+
+        for row in [blank_deposit, long_withdrawal] {
+            assert_eq!(
+                validate_record(&row, &config),
+                Ok(row.clone().into_transaction(&config).unwrap())
+            );
+        }
+    }
+
+    #[test]
+    fn test_client_id_0_is_rejected_by_conversion_and_validation() {
+        assert!(matches!(
+            Transaction::try_from(record(TransactionType::Deposit, 0, Some(dec!(1)))),
+            Err(TransactionRecordError::ReservedClientId)
+        ));
+        assert_eq!(
+            validate_record(
+                &record(TransactionType::Resolve, 0, None),
+                &StreamConfig::default()
+            ),
+            Err(vec![RowError::ReservedClientId])
+        );
+    }
+
+    #[test]
+    fn test_validate_record_accepts_valid_rows() {
+        assert_eq!(
+            validate_record(
+                &record(TransactionType::Deposit, 1, Some(dec!(1.5000))),
+                &StreamConfig::default()
+            ),
+            Ok(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(1.5000),
+            })
+        );
+        assert_eq!(
+            validate_record(
+                &record(TransactionType::Chargeback, 1, Some(dec!(-1))),
+                &StreamConfig::default()
+            ),
+            Ok(Transaction::Chargeback { client: 1, tx: 1 })
+        );
+    }
+
+    #[test]
+    fn test_conversion_errors_map_to_their_row_error() {
+        assert_eq!(
+            RowError::from(TransactionRecordError::MissingAmountError),
+            RowError::MissingAmount
+        );
+        assert_eq!(
+            RowError::from(TransactionRecordError::NonPositiveAmount(dec!(-1))),
+            RowError::NonPositiveAmount(dec!(-1))
+        );
+        assert_eq!(
+            RowError::from(TransactionRecordError::TooManyDecimalPlaces(dec!(1.00001))),
+            RowError::TooManyDecimalPlaces(dec!(1.00001))
+        );
+        assert_eq!(
+            RowError::from(TransactionRecordError::FutureDated(100)),
+            RowError::Invalid("Transaction dated in the future: 100".to_string())
+        );
+    }
+
+    #[test]
+    fn test_non_positive_deposit_and_withdrawal_amounts_are_rejected() {
+        for (r#type, amount) in [
+            (TransactionType::Deposit, dec!(-50)),
+            (TransactionType::Deposit, dec!(0)),
+            (TransactionType::Withdrawal, dec!(-0.0001)),
+        ] {
+            let result = Transaction::try_from(record(r#type, 1, Some(amount)));
+            assert!(
+                matches!(result, Err(TransactionRecordError::NonPositiveAmount(a)) if a == amount)
+            );
+        }
+    }
+
+    #[test]
+    fn test_amounts_with_more_than_4_decimal_places_are_rejected_unless_rounding() {
+        let deposit = |amount| record(TransactionType::Deposit, 1, Some(amount));
+        assert_eq!(
+            Transaction::try_from(deposit(dec!(5.1234))).unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(5.1234),
+            }
+        );
+        assert!(matches!(
+            Transaction::try_from(deposit(dec!(5.12345))),
+            Err(TransactionRecordError::TooManyDecimalPlaces(a)) if a == dec!(5.12345)
+        ));
+
+        let config = StreamConfig {
+            round_excess_decimal_places: true,
+            ..Default::default()
+        };
+        for (amount, rounded) in [
+            (dec!(5.12345), dec!(5.1234)),
+            (dec!(5.12355), dec!(5.1236)),
+            (dec!(5.123451), dec!(5.1235)),
+            (dec!(5.1234), dec!(5.1234)),
+        ] {
+            assert_eq!(
+                deposit(amount).into_transaction(&config).unwrap(),
+                Transaction::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: rounded,
+                }
+            );
         }
     }
 }
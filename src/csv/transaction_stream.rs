@@ -1,6 +1,13 @@
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use csv_async::StringRecord;
 use futures::StreamExt;
+use tokio::io::{AsyncRead, ReadBuf};
 
-use super::{TransactionRecord, TransactionRecordError};
+use super::{StreamConfig, TransactionRecord, TransactionRecordError};
 use crate::domain::Transaction;
 
 pub async fn create_transaction_stream<R>(
@@ -9,17 +16,171 @@ pub async fn create_transaction_stream<R>(
 where
     R: tokio::io::AsyncRead + Unpin + Send + 'static,
 {
-    csv_async::AsyncReaderBuilder::new()
+    create_transaction_stream_with_config(reader, StreamConfig::default()).await
+}
+
+/// Like `create_transaction_stream`, for input whose fields are separated by `delimiter`
+/// instead of commas, e.g. TSV
+pub async fn create_transaction_stream_with_delimiter<R>(
+    reader: R,
+    delimiter: u8,
+) -> impl futures::Stream<Item = Result<Transaction, TransactionRecordError>>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let config = StreamConfig {
+        delimiter: Some(delimiter),
+        ..Default::default()
+    };
+    create_transaction_stream_with_config(reader, config).await
+}
+
+pub async fn create_transaction_stream_with_config<R>(
+    reader: R,
+    config: StreamConfig,
+) -> impl futures::Stream<Item = Result<Transaction, TransactionRecordError>>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    create_numbered_transaction_stream(reader, config)
+        .await
+        .map(|(_, result)| result)
+}
+
+/// Like `create_transaction_stream_with_config`, also yielding the 1-based line
+/// each record starts on (0 if `csv_async` doesn't know it).
+pub async fn create_numbered_transaction_stream<R>(
+    reader: R,
+    config: StreamConfig,
+) -> impl futures::Stream<Item = (u64, Result<Transaction, TransactionRecordError>)>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let reader = LastByteTracker::new(reader);
+    let last_byte = reader.last_byte.clone();
+    let ignore_truncated = config.ignore_truncated_final_record;
+    let mut csv_reader = csv_async::AsyncReaderBuilder::new()
+        .delimiter(config.delimiter.unwrap_or(b','))
         // trim whitespaces if we encounter them
         .trim(csv_async::Trim::All)
         // to omit the last comma for dispute|resolve|chargeback lines
         .flexible(true)
-        .create_deserializer(reader)
-        .into_deserialize::<TransactionRecord>()
-        .map(|r| match r {
-            Ok(r) => r.try_into(),
-            Err(e) => Err(e.into()),
+        .create_reader(reader);
+    // Without a header no record can be parsed: the error is yielded alone, ending the stream
+    let (headers, header_error) = match csv_reader.headers().await {
+        Ok(headers) => (headers.clone(), None),
+        Err(e) => {
+            let line = e.position().map_or(1, |p| p.line());
+            (StringRecord::new(), Some((line, Err(e.into()))))
+        }
+    };
+    let has_headers = header_error.is_none();
+    let repeated_headers = headers.clone();
+
+    let records = csv_reader
+        .into_records()
+        .take_while(move |_| futures::future::ready(has_headers))
+        // skip the header if it's repeated, e.g. when chaining multiple inputs
+        .filter(move |r| {
+            let is_header = matches!(r, Ok(r) if *r == repeated_headers);
+            futures::future::ready(!is_header)
+        })
+        .map(move |r| match r {
+            Ok(r) => (
+                r.position().map_or(0, |p| p.line()),
+                parse_record(&r, &headers, &config),
+            ),
+            Err(e) => (e.position().map_or(0, |p| p.line()), Err(e.into())),
+        })
+        .map(Some)
+        .chain(futures::stream::iter([None]))
+        // Errors are held back until the next record, so an error in the final record
+        // can be reported as a truncation if the input doesn't end with a newline.
+        .scan(None, move |held_error, next| {
+            let mut items = Vec::with_capacity(2);
+            match next {
+                Some(item) => {
+                    items.extend(held_error.take());
+                    if item.1.is_err() {
+                        *held_error = Some(item);
+                    } else {
+                        items.push(item);
+                    }
+                }
+                None => {
+                    let ends_with_newline =
+                        matches!(last_byte.load(Ordering::Relaxed), b'\n' | b'\r');
+                    match held_error.take() {
+                        // an IO error is never a truncation
+                        Some(item) if ends_with_newline || is_io_error(&item.1) => items.push(item),
+                        Some(_) if ignore_truncated => {}
+                        Some((line, _)) => {
+                            items.push((line, Err(TransactionRecordError::TruncatedRecord(line))))
+                        }
+                        None => {}
+                    }
+                }
+            }
+            futures::future::ready(Some(items))
         })
+        .flat_map(futures::stream::iter);
+    futures::stream::iter(header_error).chain(records)
+}
+
+fn is_io_error(result: &Result<Transaction, TransactionRecordError>) -> bool {
+    match result {
+        Err(TransactionRecordError::CsvError(e)) => e.is_io_error(),
+        Err(TransactionRecordError::IoError(_)) => true,
+        _ => false,
+    }
+}
+
+/// Remembers the last byte read from `inner`, ignoring trailing spaces and tabs
+struct LastByteTracker<R> {
+    inner: R,
+    last_byte: Arc<AtomicU8>,
+}
+
+impl<R> LastByteTracker<R> {
+    fn new(inner: R) -> Self {
+        Self {
+            inner,
+            last_byte: Arc::new(AtomicU8::new(b'\n')),
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LastByteTracker<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let already_filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        let last_byte = buf.filled()[already_filled..]
+            .iter()
+            .rev()
+            .find(|byte| !matches!(byte, b' ' | b'\t'));
+        if let Some(&byte) = last_byte {
+            self.last_byte.store(byte, Ordering::Relaxed);
+        }
+        result
+    }
+}
+
+fn parse_record(
+    record: &StringRecord,
+    headers: &StringRecord,
+    config: &StreamConfig,
+) -> Result<Transaction, TransactionRecordError> {
+    // with `flexible(true)` extra trailing columns are otherwise ignored
+    if config.strict_columns && record.len() > headers.len() {
+        return Err(TransactionRecordError::UnexpectedColumns(record.len()));
+    }
+    record
+        .deserialize::<TransactionRecord>(Some(headers))?
+        .into_transaction(config)
 }
 
 #[cfg(test)]
@@ -57,6 +218,22 @@ dispute,1,1,";
         );
     }
 
+    #[tokio::test]
+    async fn test_transaction_stream_accepts_withdraw_as_withdrawal() {
+        let test_data = "type,client,tx,amount
+withdraw,1,2,10";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: Decimal::new(10, 0)
+            }
+        );
+    }
+
     #[tokio::test]
     async fn test_transaction_stream_works_with_whitespaces() {
         let test_data = "
@@ -188,4 +365,279 @@ dispute,1,1,";
             Transaction::Chargeback { client: 2, tx: 2 }
         );
     }
+
+    #[tokio::test]
+    async fn test_transaction_stream_parses_freeze_and_unfreeze() {
+        let test_data = "
+            type, client, tx, amount
+            freeze, 3, 3
+            unfreeze, 3, 4, 1.0
+        ";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Freeze { client: 3, tx: 3 }
+        );
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Unfreeze { client: 3, tx: 4 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tab_delimited_input_parses_like_comma_delimited() {
+        let comma_data = "type,client,tx,amount\ndeposit,1,1,1.0\nwithdrawal,1,2,0.5\ndispute,1,1,\nchargeback,1,1,\n";
+        let tab_data = "type\tclient\ttx\tamount\ndeposit\t1\t1\t1.0\nwithdrawal\t1\t2\t0.5\ndispute\t1\t1\nchargeback\t1\t1\t\n";
+
+        let expected: Vec<_> = create_transaction_stream(comma_data.as_bytes())
+            .await
+            .map(Result::unwrap)
+            .collect()
+            .await;
+        let parsed: Vec<_> = create_transaction_stream_with_delimiter(tab_data.as_bytes(), b'\t')
+            .await
+            .map(Result::unwrap)
+            .collect()
+            .await;
+
+        assert_eq!(expected.len(), 4);
+        assert_eq!(parsed, expected);
+    }
+
+    #[tokio::test]
+    async fn test_header_read_errors_are_yielded_and_end_the_stream() {
+        let test_data = b"type, client, tx, am\xffount\ndeposit, 1, 1, 1.0\n";
+        let transaction_stream =
+            create_numbered_transaction_stream(&test_data[..], StreamConfig::default()).await;
+
+        let items: Vec<_> = transaction_stream.collect().await;
+
+        assert_eq!(items.len(), 1);
+        assert!(matches!(
+            &items[0],
+            (1, Err(TransactionRecordError::CsvError(e)))
+                if matches!(e.kind(), csv_async::ErrorKind::Utf8 { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_skips_repeated_header() {
+        let test_data = "
+            type, client, tx, amount
+            deposit, 1, 1, 1.0
+            type, client, tx, amount
+            dispute, 1, 1
+        ";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Decimal::new(1, 0),
+            }
+        );
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Dispute { client: 1, tx: 1 }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_parses_dispute_with_amount_as_partial_dispute() {
+        let test_data = "
+            type, client, tx, amount
+            dispute, 1, 1, 0.5
+            dispute, 1, 2,
+            dispute, 1, 3, -1.23456
+        ";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::PartialDispute {
+                client: 1,
+                tx: 1,
+                amount: Decimal::new(5, 1),
+            }
+        );
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Dispute { client: 1, tx: 2 }
+        );
+        // left to the ledger to validate, if it uses the amount at all
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::PartialDispute {
+                client: 1,
+                tx: 3,
+                amount: Decimal::new(-123456, 5),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_rejects_future_dated_transactions() {
+        let test_data = "
+            type, client, tx, amount, timestamp
+            deposit, 1, 1, 1.0, 100
+            deposit, 1, 2, 1.0, 101
+            deposit, 1, 3, 1.0,
+        ";
+        let config = StreamConfig {
+            as_of: Some(100),
+            ..Default::default()
+        };
+        let mut transaction_stream =
+            create_transaction_stream_with_config(test_data.as_bytes(), config).await;
+
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Decimal::new(1, 0),
+            }
+        );
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::FutureDated(101))
+        ));
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 3,
+                amount: Decimal::new(1, 0),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_ignores_timestamps_by_default() {
+        let test_data = "
+            type, client, tx, amount, timestamp
+            deposit, 1, 1, 1.0, 4102444800
+        ";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert!(transaction_stream.next().await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_ignores_extra_trailing_columns_by_default() {
+        let test_data = "
+            type, client, tx, amount
+            deposit, 1, 1, 5, garbage
+        ";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Decimal::new(5, 0),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_rejects_extra_trailing_columns_when_strict() {
+        let test_data = "
+            type, client, tx, amount
+            deposit, 1, 1, 5, garbage
+            deposit, 1, 2, 5
+        ";
+        let config = StreamConfig {
+            strict_columns: true,
+            ..Default::default()
+        };
+        let mut transaction_stream =
+            create_transaction_stream_with_config(test_data.as_bytes(), config).await;
+
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::UnexpectedColumns(5))
+        ));
+        assert!(transaction_stream.next().await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_blank_deposit_amount_is_zero_only_when_enabled() {
+        let test_data = "
+            type, client, tx, amount
+            deposit, 1, 1,
+            withdrawal, 1, 2,
+        ";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::MissingAmountError)
+        ));
+
+        let config = StreamConfig {
+            blank_amount_as_zero: true,
+            ..Default::default()
+        };
+        let mut transaction_stream =
+            create_transaction_stream_with_config(test_data.as_bytes(), config).await;
+        assert_eq!(
+            transaction_stream.next().await.unwrap().unwrap(),
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: Decimal::ZERO,
+            }
+        );
+        // only deposits are lenient
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::MissingAmountError)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_reports_truncated_final_record() {
+        let test_data = "type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 1, 2";
+        let mut transaction_stream = create_transaction_stream(test_data.as_bytes()).await;
+
+        assert!(transaction_stream.next().await.unwrap().is_ok());
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::TruncatedRecord(3))
+        ));
+        assert!(transaction_stream.next().await.is_none());
+
+        let config = StreamConfig {
+            ignore_truncated_final_record: true,
+            ..Default::default()
+        };
+        let mut transaction_stream =
+            create_transaction_stream_with_config(test_data.as_bytes(), config).await;
+        assert!(transaction_stream.next().await.unwrap().is_ok());
+        assert!(transaction_stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transaction_stream_keeps_error_of_complete_final_record() {
+        let test_data = "type, client, tx, amount
+deposit, 1, 2
+";
+        let config = StreamConfig {
+            ignore_truncated_final_record: true,
+            ..Default::default()
+        };
+        let mut transaction_stream =
+            create_transaction_stream_with_config(test_data.as_bytes(), config).await;
+
+        assert!(matches!(
+            transaction_stream.next().await.unwrap(),
+            Err(TransactionRecordError::MissingAmountError)
+        ));
+    }
 }
@@ -0,0 +1,61 @@
+use std::collections::HashSet;
+
+use crate::domain::{Decimal, TransactionType};
+
+/// Policies applied by the `Ledger` (and the `Wallet`s it creates) while processing
+/// transactions. `EngineConfig::default()` keeps the behavior described in the README.
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct EngineConfig {
+    /// Reject disputes of a `Resolved` deposit, so a deposit can be disputed at most once.
+    /// By default a resolved deposit can be disputed again.
+    pub forbid_redispute: bool,
+    /// Maximum number of times a single deposit can be disputed.
+    pub max_dispute_cycles: Option<u32>,
+    /// What a locked account still accepts.
+    pub lock_policy: LockPolicy,
+    /// Queue deposits made to a locked account instead of rejecting them.
+    /// Queued deposits are applied in order when the account gets unfrozen.
+    /// Only applies with `LockPolicy::RejectAll`.
+    pub queue_locked_deposits: bool,
+    /// Resolving an already `Resolved` deposit is a no-op instead of an error.
+    pub idempotent_resolve: bool,
+    /// Reject withdrawals that would leave less than this `available` amount.
+    pub require_minimum_balance: Option<Decimal>,
+    /// Dispute only the amount carried by a dispute row instead of the whole deposit.
+    /// When off, the dispute amount is ignored.
+    pub partial_disputes: bool,
+    /// Reject deposits of less than this amount, whatever path they come through.
+    pub min_deposit: Option<Decimal>,
+    /// Reject deposits that would push a client's `available + held` over this ceiling.
+    pub max_client_balance: Option<Decimal>,
+    /// What a dispute of a zero-amount deposit does.
+    pub zero_amount_disputes: ZeroAmountDisputes,
+    /// Only apply transactions of these types, the others are skipped without
+    /// touching the ledger (and without being reported as errors).
+    pub type_filter: Option<HashSet<TransactionType>>,
+    /// Resolve a dispute that wasn't resolved or charged back within this many following
+    /// transactions (over all clients), returning its funds from `held` to `available`.
+    pub auto_resolve_after: Option<u64>,
+}
+
+/// Transactions accepted by an account locked by a chargeback
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum LockPolicy {
+    /// Every transaction is rejected with `WalletError::AccountLocked`
+    #[default]
+    RejectAll,
+    /// Deposits are still applied, anything else is rejected
+    AllowDeposits,
+}
+
+/// Handling of disputes of a deposit of zero, which can't move any funds
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum ZeroAmountDisputes {
+    /// Disputed like any other deposit, moving nothing to `held`
+    #[default]
+    Allow,
+    /// Rejected with `WalletError::ZeroAmountDispute`
+    Reject,
+    /// Accepted as a no-op, the deposit stays undisputed
+    Ignore,
+}
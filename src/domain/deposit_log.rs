@@ -1,20 +1,30 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+
 use crate::domain::Decimal;
 
 #[allow(clippy::enum_variant_names)]
-#[derive(thiserror::Error, Debug, PartialEq)]
+#[derive(thiserror::Error, Debug, PartialEq, Clone)]
 pub enum DepositLogError {
-    #[error("Can't dispute transaction, only `New` transactions are disputable")]
+    #[error("Can't dispute transaction, only `New` or `Resolved` transactions are disputable")]
     CantDispute,
+    #[error("Deposit is already disputed")]
+    AlreadyDisputed,
+    #[error("Can't dispute a `Resolved` transaction again")]
+    CantRedispute,
     #[error("Can't resolve undisputed deposit")]
     CantResolveUndisputed,
     #[error("Can't chargeback undisputed deposit")]
     CantChargebackUndisputed,
+    #[error("Disputed amount must be positive and can't exceed the deposit")]
+    InvalidDisputedAmount,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, BorshSerialize, BorshDeserialize)]
 pub struct DepositLog {
     amount: Decimal,
     status: DepositStatus,
+    dispute_cycles: u32,
+    disputed_amount: Decimal,
 }
 
 impl DepositLog {
@@ -22,32 +32,113 @@ impl DepositLog {
         Self {
             amount,
             status: DepositStatus::New,
+            dispute_cycles: 0,
+            disputed_amount: Decimal::ZERO,
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-enum DepositStatus {
+/// Where a deposit is in the dispute lifecycle
+#[derive(Debug, PartialEq, Eq, Clone, Copy, BorshSerialize, BorshDeserialize)]
+pub enum DepositStatus {
     New,
     Disputed,
     Resolved,
     Chargedback,
 }
 
+impl DepositStatus {
+    pub fn label(&self) -> &'static str {
+        match self {
+            DepositStatus::New => "new",
+            DepositStatus::Disputed => "disputed",
+            DepositStatus::Resolved => "resolved",
+            DepositStatus::Chargedback => "chargedback",
+        }
+    }
+}
+
 impl DepositLog {
     pub fn get_amount(&self) -> Decimal {
         self.amount
     }
+    /// Number of times this deposit has been disputed
+    pub fn get_dispute_cycles(&self) -> u32 {
+        self.dispute_cycles
+    }
+    /// Amount held by the latest dispute, the whole deposit unless partially disputed
+    pub fn get_disputed_amount(&self) -> Decimal {
+        self.disputed_amount
+    }
+    pub fn validate_disputed_amount(&self, amount: Decimal) -> Result<(), DepositLogError> {
+        if amount <= Decimal::ZERO || amount > self.amount {
+            return Err(DepositLogError::InvalidDisputedAmount);
+        }
+        Ok(())
+    }
+    /// Part of the deposit that a dispute can still hold: all of it unless `Disputed`,
+    /// nothing once `Chargedback`
+    pub fn get_remaining_disputable(&self) -> Decimal {
+        match self.status {
+            DepositStatus::New | DepositStatus::Resolved => self.amount,
+            DepositStatus::Disputed => self.amount - self.disputed_amount,
+            DepositStatus::Chargedback => Decimal::ZERO,
+        }
+    }
+    /// Holds `amount` more of a partially `Disputed` deposit, all of the remaining
+    /// disputable amount if `None`. Returns the amount added to the dispute.
+    pub fn extend_dispute(&mut self, amount: Option<Decimal>) -> Result<Decimal, DepositLogError> {
+        let remaining = self.get_remaining_disputable();
+        if self.status != DepositStatus::Disputed {
+            return Err(DepositLogError::CantDispute);
+        }
+        if remaining.is_zero() {
+            return Err(DepositLogError::AlreadyDisputed);
+        }
+        let amount = amount.unwrap_or(remaining);
+        if amount <= Decimal::ZERO || amount > remaining {
+            return Err(DepositLogError::InvalidDisputedAmount);
+        }
+        self.disputed_amount += amount;
+        Ok(amount)
+    }
+    /// Limits the current dispute to part of the deposit
+    pub fn set_disputed_amount(&mut self, amount: Decimal) -> Result<(), DepositLogError> {
+        self.validate_disputed_amount(amount)?;
+        self.disputed_amount = amount;
+        Ok(())
+    }
+    /// A `Resolved` deposit can be disputed again, `Chargedback` is terminal.
     pub fn set_disputed(&mut self) -> Result<(), DepositLogError> {
         match self.status {
-            DepositStatus::New => {
+            DepositStatus::New | DepositStatus::Resolved => {
                 self.status = DepositStatus::Disputed;
+                self.dispute_cycles += 1;
+                self.disputed_amount = self.amount;
                 Ok(())
             }
-            _ => Err(DepositLogError::CantDispute),
+            DepositStatus::Disputed => Err(DepositLogError::AlreadyDisputed),
+            DepositStatus::Chargedback => Err(DepositLogError::CantDispute),
         }
     }
 
+    pub fn is_disputed(&self) -> bool {
+        self.status == DepositStatus::Disputed
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        self.status == DepositStatus::Resolved
+    }
+
+    /// Whether the deposit has been disputed at least once, whatever its current status
+    pub fn was_disputed(&self) -> bool {
+        self.dispute_cycles > 0
+    }
+
+    pub fn status(&self) -> DepositStatus {
+        self.status
+    }
+
     pub fn set_resolved(&mut self) -> Result<(), DepositLogError> {
         match self.status {
             DepositStatus::Disputed => {
@@ -58,6 +149,8 @@ impl DepositLog {
         }
     }
 
+    /// Only a currently `Disputed` deposit can be charged back. A `Resolved` deposit
+    /// needs a new dispute first.
     pub fn set_chargedback(&mut self) -> Result<(), DepositLogError> {
         match self.status {
             DepositStatus::Disputed => {
@@ -87,4 +180,61 @@ mod tests {
         let result = deposit_log.set_chargedback();
         assert_eq!(Err(DepositLogError::CantChargebackUndisputed), result);
     }
+
+    #[test]
+    fn test_set_disputed_works_for_resolved_deposit_but_not_chargedback() {
+        let mut deposit_log = DepositLog::new(dec!(1));
+        deposit_log.set_disputed().unwrap();
+        assert_eq!(
+            Err(DepositLogError::AlreadyDisputed),
+            deposit_log.set_disputed()
+        );
+        deposit_log.set_resolved().unwrap();
+
+        deposit_log.set_disputed().unwrap();
+        deposit_log.set_chargedback().unwrap();
+        assert_eq!(
+            Err(DepositLogError::CantDispute),
+            deposit_log.set_disputed()
+        );
+        assert_eq!(2, deposit_log.get_dispute_cycles());
+        assert_eq!(DepositStatus::Chargedback, deposit_log.status());
+    }
+
+    #[test]
+    fn test_extend_dispute_holds_at_most_the_remaining_amount() {
+        let mut deposit_log = DepositLog::new(dec!(10));
+        assert_eq!(
+            Err(DepositLogError::CantDispute),
+            deposit_log.extend_dispute(None)
+        );
+        deposit_log.set_disputed().unwrap();
+        deposit_log.set_disputed_amount(dec!(3)).unwrap();
+        assert_eq!(dec!(7), deposit_log.get_remaining_disputable());
+
+        assert_eq!(
+            Err(DepositLogError::InvalidDisputedAmount),
+            deposit_log.extend_dispute(Some(dec!(8)))
+        );
+        assert_eq!(Ok(dec!(2)), deposit_log.extend_dispute(Some(dec!(2))));
+        assert_eq!(Ok(dec!(5)), deposit_log.extend_dispute(None));
+        assert_eq!(dec!(10), deposit_log.get_disputed_amount());
+        assert_eq!(
+            Err(DepositLogError::AlreadyDisputed),
+            deposit_log.extend_dispute(None)
+        );
+        assert_eq!(1, deposit_log.get_dispute_cycles());
+    }
+
+    #[test]
+    fn test_set_disputed_amount_fails_for_amount_exceeding_deposit() {
+        let mut deposit_log = DepositLog::new(dec!(10));
+        deposit_log.set_disputed().unwrap();
+        assert_eq!(
+            Err(DepositLogError::InvalidDisputedAmount),
+            deposit_log.set_disputed_amount(dec!(11))
+        );
+        deposit_log.set_disputed_amount(dec!(4)).unwrap();
+        assert_eq!(dec!(4), deposit_log.get_disputed_amount());
+    }
 }
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use rust_decimal::RoundingStrategy;
+use serde::Serialize;
+
+use super::{ClientId, Decimal, Ledger, WalletSnapshot};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OutputColumn {
+    Client,
+    Available,
+    Held,
+    Total,
+    Locked,
+    TxCount,
+    DisputeCount,
+}
+
+impl OutputColumn {
+    pub fn name(&self) -> &'static str {
+        match self {
+            OutputColumn::Client => "client",
+            OutputColumn::Available => "available",
+            OutputColumn::Held => "held",
+            OutputColumn::Total => "total",
+            OutputColumn::Locked => "locked",
+            OutputColumn::TxCount => "tx_count",
+            OutputColumn::DisputeCount => "dispute_count",
+        }
+    }
+
+    fn value(&self, client_id: ClientId, snapshot: &WalletSnapshot) -> String {
+        match self {
+            OutputColumn::Client => client_id.to_string(),
+            OutputColumn::Available => snapshot.available.to_string(),
+            OutputColumn::Held => snapshot.held.to_string(),
+            OutputColumn::Total => snapshot.total.to_string(),
+            OutputColumn::Locked => snapshot.locked.to_string(),
+            OutputColumn::TxCount => snapshot.tx_count.to_string(),
+            OutputColumn::DisputeCount => snapshot.dispute_count.to_string(),
+        }
+    }
+}
+
+/// A client as emitted by `Ledger::dump_json_to_writer`.
+/// Decimals are rendered as strings so no precision gets lost to floats.
+#[derive(Serialize, Debug)]
+pub(crate) struct JsonRow {
+    client: ClientId,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+impl JsonRow {
+    pub(crate) fn new(client: ClientId, snapshot: &WalletSnapshot) -> Self {
+        Self {
+            client,
+            available: snapshot.available.to_string(),
+            held: snapshot.held.to_string(),
+            total: snapshot.total.to_string(),
+            locked: snapshot.locked,
+        }
+    }
+}
+
+/// Columns emitted by `Ledger::dump_to_writer_with_options`, in order.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OutputColumns(pub Vec<OutputColumn>);
+
+impl Default for OutputColumns {
+    fn default() -> Self {
+        Self(vec![
+            OutputColumn::Client,
+            OutputColumn::Available,
+            OutputColumn::Held,
+            OutputColumn::Total,
+            OutputColumn::Locked,
+        ])
+    }
+}
+
+impl OutputColumns {
+    pub fn header(&self) -> String {
+        if *self == Self::default() {
+            return Ledger::CSV_HEADER.to_string();
+        }
+        let names: Vec<_> = self.0.iter().map(OutputColumn::name).collect();
+        format!("{}\n", names.join(", "))
+    }
+
+    pub fn row(&self, client_id: ClientId, snapshot: &WalletSnapshot) -> String {
+        let values: Vec<_> = self
+            .0
+            .iter()
+            .map(|column| column.value(client_id, snapshot))
+            .collect();
+        format!("{}\n", values.join(", "))
+    }
+}
+
+/// Order of the rows in the output
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub enum OutputOrder {
+    /// Whatever order the `Ledger` stores its clients in
+    #[default]
+    Unordered,
+    /// Largest `total` first, ties ordered by client id
+    ByTotalDesc,
+    /// Ascending client id
+    ByClientId,
+}
+
+impl OutputOrder {
+    pub(crate) fn sort(&self, rows: &mut [(ClientId, WalletSnapshot)]) {
+        match self {
+            OutputOrder::Unordered => {}
+            OutputOrder::ByClientId => rows.sort_by_key(|(client_id, _)| *client_id),
+            OutputOrder::ByTotalDesc => rows
+                .sort_by(|(id_a, a), (id_b, b)| b.total.cmp(&a.total).then_with(|| id_a.cmp(id_b))),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct DumpOptions {
+    pub columns: OutputColumns,
+    pub order: OutputOrder,
+    /// Sum the rows sharing a client id into a single row. The `Ledger` keeps one
+    /// wallet per client, so this is a defensive no-op unless rows get duplicated.
+    pub collapse_duplicate_clients: bool,
+    /// Flush the writer after every row, for consumers reading the output line by line.
+    pub flush_each_row: bool,
+    /// Build the whole dump in memory and write it with a single `write_all`, trading
+    /// memory for fewer writes. `flush_each_row` doesn't apply then.
+    pub single_write: bool,
+    /// Leave out the newline after the last line of the dump.
+    pub omit_trailing_newline: bool,
+    /// Render the balances with exactly this many decimal places, padding with zeros
+    /// (`25.0000`) and rounding half to even. As stored when `None`.
+    pub decimal_places: Option<u32>,
+}
+
+/// `snapshot` with its balances rendered to exactly `decimal_places`
+pub(crate) fn with_decimal_places(snapshot: WalletSnapshot, decimal_places: u32) -> WalletSnapshot {
+    let fixed = |balance: Decimal| {
+        let mut balance =
+            balance.round_dp_with_strategy(decimal_places, RoundingStrategy::MidpointNearestEven);
+        balance.rescale(decimal_places);
+        balance
+    };
+    WalletSnapshot {
+        available: fixed(snapshot.available),
+        held: fixed(snapshot.held),
+        total: fixed(snapshot.total),
+        ..snapshot
+    }
+}
+
+/// Sums rows with the same client id, keeping the position of the first occurrence.
+/// A collapsed client is locked if any of its rows is locked.
+pub(crate) fn collapse_duplicate_clients(
+    rows: Vec<(ClientId, WalletSnapshot)>,
+) -> Vec<(ClientId, WalletSnapshot)> {
+    let mut positions: HashMap<ClientId, usize> = HashMap::with_capacity(rows.len());
+    let mut collapsed: Vec<(ClientId, WalletSnapshot)> = Vec::with_capacity(rows.len());
+    for (client_id, snapshot) in rows {
+        match positions.get(&client_id) {
+            Some(&position) => {
+                let existing = &mut collapsed[position].1;
+                existing.available += snapshot.available;
+                existing.held += snapshot.held;
+                existing.total += snapshot.total;
+                existing.locked |= snapshot.locked;
+                existing.tx_count += snapshot.tx_count;
+                existing.dispute_count += snapshot.dispute_count;
+            }
+            None => {
+                positions.insert(client_id, collapsed.len());
+                collapsed.push((client_id, snapshot));
+            }
+        }
+    }
+    collapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_default_columns_match_the_csv_header() {
+        let names: Vec<_> = OutputColumns::default()
+            .0
+            .iter()
+            .map(OutputColumn::name)
+            .collect();
+        assert_eq!(format!("{}\n", names.join(", ")), Ledger::CSV_HEADER);
+    }
+
+    #[test]
+    fn test_collapse_duplicate_clients_sums_rows_per_client() {
+        let snapshot = |available, locked| WalletSnapshot {
+            available,
+            held: dec!(1),
+            total: available + dec!(1),
+            locked,
+            tx_count: 1,
+            dispute_count: 1,
+        };
+        let rows = vec![
+            (1, snapshot(dec!(10), false)),
+            (2, snapshot(dec!(5), false)),
+            (1, snapshot(dec!(2), true)),
+        ];
+
+        let expected = vec![
+            (
+                1,
+                WalletSnapshot {
+                    available: dec!(12),
+                    held: dec!(2),
+                    total: dec!(14),
+                    locked: true,
+                    tx_count: 2,
+                    dispute_count: 2,
+                },
+            ),
+            (2, snapshot(dec!(5), false)),
+        ];
+        assert_eq!(collapse_duplicate_clients(rows), expected);
+    }
+
+    #[test]
+    fn test_with_decimal_places_pads_and_rounds_balances() {
+        let snapshot = WalletSnapshot {
+            available: dec!(25),
+            held: dec!(0.12345),
+            total: dec!(25.12345),
+            ..Default::default()
+        };
+
+        let fixed = with_decimal_places(snapshot, 4);
+        assert_eq!(fixed.available.to_string(), "25.0000");
+        assert_eq!(fixed.held.to_string(), "0.1234");
+        assert_eq!(fixed.total.to_string(), "25.1234");
+        assert_eq!(with_decimal_places(snapshot, 0).held.to_string(), "0");
+    }
+}
@@ -1,9 +1,19 @@
-use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
-use parking_lot::{MappedRwLockReadGuard, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use tracing::info;
+use borsh::{BorshDeserialize, BorshSerialize};
+use parking_lot::{Mutex, RwLock};
+use tracing::{info, warn};
 
-use super::{ClientId, Transaction, Wallet, WalletError};
+use super::dump::{collapse_duplicate_clients, with_decimal_places, JsonRow};
+use super::wallet::ensure_valid_deposit;
+use super::{
+    client_namespace, ClientId, Decimal, DumpOptions, EngineConfig, InMemoryStore, LedgerMetrics,
+    LedgerSnapshot, LockPolicy, OutputColumn, OutputColumns, OutputOrder, Transaction,
+    TransactionId, TransactionType, Wallet, WalletError, WalletSnapshot, WalletStore,
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum LedgerError {
@@ -13,111 +23,2822 @@ pub enum LedgerError {
     IoError(#[from] std::io::Error),
     #[error("Wallet error: {0}")]
     WalletError(#[from] WalletError),
+    #[error("Transaction id `{0}` was already used")]
+    DuplicateTransactionId(TransactionId),
+    #[error("Transaction `{tx}` belongs to client `{expected}`, not `{actual}`")]
+    ClientMismatch {
+        tx: TransactionId,
+        expected: ClientId,
+        actual: ClientId,
+    },
 }
 
+/// Cheap summary of the `Ledger`, suitable for a health/readiness endpoint
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct EngineHealth {
+    pub clients: usize,
+    pub locked: usize,
+    pub total_held: Decimal,
+}
+
+/// Balances summed over all clients, see `Ledger::aggregate`
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct LedgerAggregate {
+    pub clients: usize,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    /// Number of locked accounts
+    pub locked: usize,
+}
+
+/// Sequence numbers of the processed transactions and of the open disputes,
+/// used to auto-resolve disputes after `EngineConfig::auto_resolve_after` transactions
 #[derive(Default, Debug)]
-pub struct Ledger {
-    clients: RwLock<HashMap<ClientId, Mutex<Wallet>>>,
+struct DisputeAging {
+    sequence: AtomicU64,
+    /// `(sequence, client, tx, dispute cycle)`, ordered by sequence
+    open_disputes: Mutex<VecDeque<(u64, ClientId, TransactionId, u32)>>,
+}
+
+impl Clone for DisputeAging {
+    fn clone(&self) -> Self {
+        Self {
+            sequence: AtomicU64::new(self.sequence.load(Ordering::Relaxed)),
+            open_disputes: Mutex::new(self.open_disputes.lock().clone()),
+        }
+    }
+}
+
+/// Owner of every deposit and withdrawal tx id. Tx ids are unique per namespace (see
+/// `namespaced_client_id`), so tenants sharing the ledger can reuse each other's ids.
+///
+/// Split in `REGISTRY_SHARDS` maps by tx id, each behind its own lock, so deposits and
+/// withdrawals of different clients rarely contend on claiming their ids.
+#[derive(Default, Debug)]
+struct TransactionRegistry {
+    shards: [RwLock<HashMap<(u16, TransactionId), ClientId>>; REGISTRY_SHARDS],
+}
+
+const REGISTRY_SHARDS: usize = 16;
+
+impl TransactionRegistry {
+    fn shard(&self, tx: TransactionId) -> &RwLock<HashMap<(u16, TransactionId), ClientId>> {
+        &self.shards[tx as usize % REGISTRY_SHARDS]
+    }
+
+    fn entries(&self) -> Vec<((u16, TransactionId), ClientId)> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .iter()
+                    .map(|(k, v)| (*k, *v))
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    fn from_entries(entries: Vec<((u16, TransactionId), ClientId)>) -> Self {
+        let registry = Self::default();
+        for ((namespace, tx), client) in entries {
+            registry.shard(tx).write().insert((namespace, tx), client);
+        }
+        registry
+    }
+
+    /// Claims `tx` for `client`, returning the previous owner if it was already claimed
+    fn claim(&self, client: ClientId, tx: TransactionId) -> Result<(), ClientId> {
+        match self.shard(tx).write().entry((client_namespace(client), tx)) {
+            Entry::Occupied(entry) => Err(*entry.get()),
+            Entry::Vacant(entry) => {
+                entry.insert(client);
+                Ok(())
+            }
+        }
+    }
+
+    /// Gives back a claim whose transaction was rejected
+    fn release(&self, client: ClientId, tx: TransactionId) {
+        self.shard(tx)
+            .write()
+            .remove(&(client_namespace(client), tx));
+    }
+
+    fn approximate_memory_bytes(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.read().capacity())
+            .sum::<usize>()
+            * std::mem::size_of::<((u16, TransactionId), ClientId)>()
+    }
+
+    fn owner(&self, client: ClientId, tx: TransactionId) -> Option<ClientId> {
+        self.shard(tx)
+            .read()
+            .get(&(client_namespace(client), tx))
+            .copied()
+    }
+
+    /// Fails if `tx` was deposited by a client other than `client`
+    fn check_owner(&self, client: ClientId, tx: TransactionId) -> Result<(), LedgerError> {
+        match self.owner(client, tx) {
+            Some(owner) if owner != client => Err(LedgerError::ClientMismatch {
+                tx,
+                expected: owner,
+                actual: client,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Clone for TransactionRegistry {
+    fn clone(&self) -> Self {
+        Self {
+            shards: std::array::from_fn(|i| RwLock::new(self.shards[i].read().clone())),
+        }
+    }
+}
+
+/// What `Ledger::save_snapshot` persists: the wallets with their deposit logs, and the
+/// owners of the tx ids. Metrics, dispute aging and the config aren't part of it.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct Checkpoint {
+    wallets: Vec<(ClientId, Wallet)>,
+    transactions: Vec<((u16, TransactionId), ClientId)>,
+}
+
+type ApplyHookFn = dyn Fn(&Transaction, &WalletSnapshot) + Send + Sync;
+
+/// Optional callback fired with every applied transaction, see `Ledger::with_apply_hook`
+#[derive(Default, Clone)]
+struct ApplyHook(Option<Arc<ApplyHookFn>>);
+
+impl std::fmt::Debug for ApplyHook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(if self.0.is_some() { "Some(..)" } else { "None" })
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct Ledger<S: WalletStore = InMemoryStore> {
+    clients: S,
+    config: Arc<EngineConfig>,
+    metrics: LedgerMetrics,
+    dispute_aging: DisputeAging,
+    transactions: TransactionRegistry,
+    apply_hook: ApplyHook,
 }
 
 impl Ledger {
+    /// Header of the dump with the default columns, as written by `dump_to_writer`
+    pub const CSV_HEADER: &'static str = "client, available, held, total, locked\n";
+
     pub fn new() -> Ledger {
         Ledger::default()
     }
 
+    pub fn with_config(config: EngineConfig) -> Ledger {
+        Ledger::with_store(InMemoryStore::default(), config)
+    }
+
+    /// A ledger with the default config, apart from what locked accounts accept
+    pub fn new_with_policy(lock_policy: LockPolicy) -> Ledger {
+        Ledger::with_config(EngineConfig {
+            lock_policy,
+            ..Default::default()
+        })
+    }
+
+    /// Restores a ledger written by `save_snapshot`, with the default config
+    pub fn load_snapshot<R>(r: &mut R) -> Result<Ledger, LedgerError>
+    where
+        R: std::io::Read,
+    {
+        Ledger::load_snapshot_with_config(r, EngineConfig::default())
+    }
+
+    /// Restores a ledger written by `save_snapshot`, processing with `config` from then on
+    pub fn load_snapshot_with_config<R>(
+        r: &mut R,
+        config: EngineConfig,
+    ) -> Result<Ledger, LedgerError>
+    where
+        R: std::io::Read,
+    {
+        let checkpoint = Checkpoint::deserialize_reader(r)?;
+        let ledger = Ledger {
+            transactions: TransactionRegistry::from_entries(checkpoint.transactions),
+            ..Ledger::with_config(config)
+        };
+        for (client_id, mut wallet) in checkpoint.wallets {
+            wallet.set_config(ledger.config.clone());
+            let _ = ledger.clients.get_or_create(&client_id, || wallet);
+        }
+        Ok(ledger)
+    }
+}
+
+impl<S: WalletStore> Ledger<S> {
+    pub fn with_store(store: S, config: EngineConfig) -> Ledger<S> {
+        Ledger {
+            clients: store,
+            config: Arc::new(config),
+            metrics: LedgerMetrics::default(),
+            dispute_aging: DisputeAging::default(),
+            transactions: TransactionRegistry::default(),
+            apply_hook: ApplyHook::default(),
+        }
+    }
+
+    /// Calls `hook` after every applied transaction with the resulting state of the
+    /// client's wallet; rejected transactions don't fire it. It runs under the wallet's
+    /// lock, so it fires in the order the client's transactions are applied, but it must
+    /// not process transactions of the same client.
+    pub fn with_apply_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&Transaction, &WalletSnapshot) + Send + Sync + 'static,
+    {
+        self.apply_hook = ApplyHook(Some(Arc::new(hook)));
+        self
+    }
+
+    /// A deep copy without the apply hook, to simulate transactions that must not reach
+    /// whatever the hook feeds, e.g. for a dry run or a validation
+    pub fn scratch_copy(&self) -> Self {
+        Ledger {
+            apply_hook: ApplyHook::default(),
+            ..self.clone()
+        }
+    }
+
+    /// Applies the transaction synchronously on the calling thread, it never spawns.
     pub fn process_transaction(&self, transaction: Transaction) -> Result<(), LedgerError> {
-        info!(?transaction, "Processing");
+        info!(%transaction, "Processing");
+        if self.is_filtered_out(&transaction) {
+            info!(%transaction, "Skipped by the type filter");
+            self.metrics.record_skipped();
+            return Ok(());
+        }
+        let transaction_type = transaction.get_type();
+        let (client, tx) = (
+            transaction.get_client_id(),
+            transaction.get_transaction_id(),
+        );
+        let sequence = self.dispute_aging.sequence.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(window) = self.config.auto_resolve_after {
+            self.auto_resolve_stale_disputes(sequence, window);
+        }
+        let result = self.apply_transaction(transaction);
+        self.metrics.record(transaction_type, &result);
+        if result.is_ok()
+            && transaction_type == TransactionType::Dispute
+            && self.config.auto_resolve_after.is_some()
+        {
+            if let Ok(Some(cycle)) = self.with_client_locked(client, |w| w.open_dispute_cycle(tx)) {
+                self.dispute_aging
+                    .open_disputes
+                    .lock()
+                    .push_back((sequence, client, tx, cycle));
+            }
+        }
+        result
+    }
+
+    /// Processes `transactions` in order, returning the result of each of them
+    pub fn process_batch(
+        &self,
+        transactions: impl IntoIterator<Item = Transaction>,
+    ) -> Vec<Result<(), LedgerError>> {
+        transactions
+            .into_iter()
+            .map(|transaction| self.process_transaction(transaction))
+            .collect()
+    }
+
+    /// Resolves the disputes followed by more than `window` transactions. A dispute that
+    /// got resolved or charged back in the meantime (even if disputed again) is left alone.
+    fn auto_resolve_stale_disputes(&self, sequence: u64, window: u64) {
+        let mut open_disputes = self.dispute_aging.open_disputes.lock();
+        while let Some(&(disputed_at, client, tx, cycle)) = open_disputes.front() {
+            if sequence - disputed_at <= window {
+                break;
+            }
+            open_disputes.pop_front();
+            let _ = self.with_client_locked(client, |wallet| {
+                if wallet.open_dispute_cycle(tx) == Some(cycle) && wallet.resolve(tx).is_ok() {
+                    info!(client, tx, "Auto-resolved stale dispute");
+                }
+            });
+        }
+    }
+
+    fn apply_transaction(&self, transaction: Transaction) -> Result<(), LedgerError> {
+        let client = transaction.get_client_id();
+        let tx = transaction.get_transaction_id();
+        let (wallet, claimed) = match transaction {
+            // Only `Deposits` can create new clients
+            Transaction::Deposit { amount, .. } => {
+                ensure_valid_deposit(&self.config, amount)?;
+                let claimed = self.claim_transaction_id(&transaction)?;
+                let wallet = self.get_existing_or_create_client(&client);
+                (wallet, claimed)
+            }
+            _ => {
+                let wallet = self
+                    .get_existing_client(&client)
+                    .ok_or(LedgerError::InexistentClient(client))?;
+                (wallet, self.claim_transaction_id(&transaction)?)
+            }
+        };
+        let hooked = self
+            .apply_hook
+            .0
+            .as_ref()
+            .map(|hook| (hook, transaction.clone()));
+        let mut wallet = wallet.lock();
+        let result = match transaction {
+            Transaction::Unfreeze { .. } => wallet
+                .unfreeze()
+                .map(|failed| self.drop_queued_deposits(client, &failed)),
+            transaction => self.apply_to_wallet(&mut wallet, transaction),
+        };
+        match (&result, hooked) {
+            (Ok(()), Some((hook, transaction))) => hook(&transaction, &wallet.snapshot()),
+            (Err(_), _) if claimed => self.transactions.release(client, tx),
+            _ => {}
+        }
+        Ok(result?)
+    }
+
+    /// Claims the tx id of deposits and withdrawals, returning whether it was claimed now.
+    /// A deposit reusing its own client's tx id is left to the wallet to reject, while
+    /// disputes, resolves and chargebacks must come from the client owning the tx.
+    fn claim_transaction_id(&self, transaction: &Transaction) -> Result<bool, LedgerError> {
+        let (client, tx) = (
+            transaction.get_client_id(),
+            transaction.get_transaction_id(),
+        );
         match transaction {
-            Transaction::Deposit { client, tx, amount } => Ok(self
-                // Only `Deposits` can create new clients
-                .get_existing_or_create_client(&client)
-                .lock()
-                .deposit(tx, amount)?),
-            Transaction::Withdrawal { client, tx, amount } => Ok(self
-                .get_existing_client(&client)
-                .ok_or(LedgerError::InexistentClient(client))?
-                .lock()
-                .withdraw(tx, amount)?),
-            Transaction::Dispute { client, tx } => Ok(self
-                .get_existing_client(&client)
-                .ok_or(LedgerError::InexistentClient(client))?
-                .lock()
-                .dispute(tx)?),
-            Transaction::Resolve { client, tx } => Ok(self
-                .get_existing_client(&client)
-                .ok_or(LedgerError::InexistentClient(client))?
-                .lock()
-                .resolve(tx)?),
-            Transaction::Chargeback { client, tx } => Ok(self
-                .get_existing_client(&client)
-                .ok_or(LedgerError::InexistentClient(client))?
-                .lock()
-                .chargeback(tx)?),
-        }
-    }
-
-    /// Returns a MappedRwLockReadGuard because the `Mutex<Wallet>`
-    /// references the read-lock.
-    ///
-    /// We first try to find the client through a read-lock so other threads can also read
-    /// the `Ledger`. If it doesn't exist, we need a write-lock to create the Client
-    fn get_existing_or_create_client(
+            Transaction::Deposit { .. } | Transaction::Withdrawal { .. } => {
+                match self.transactions.claim(client, tx) {
+                    Ok(()) => Ok(true),
+                    Err(owner) => self.check_duplicate(transaction, owner).map(|_| false),
+                }
+            }
+            Transaction::Freeze { .. } | Transaction::Unfreeze { .. } => Ok(false),
+            _ => self.transactions.check_owner(client, tx).map(|_| false),
+        }
+    }
+
+    fn check_duplicate(
         &self,
-        client: &ClientId,
-    ) -> MappedRwLockReadGuard<Mutex<Wallet>> {
-        let read_lock = self.clients.read();
-        if read_lock.contains_key(client) {
-            RwLockReadGuard::map(read_lock, |hm| hm.get(client).unwrap())
-        } else {
-            // Drop read lock to avoid deadlock
-            drop(read_lock);
-            // We need a write-lock to add a new client
-            let mut write_lock = self.clients.write();
-            // Use entry instead of insert, in case another thread created
-            // the client in the time between the dropping of the read-lock
-            // and aquiring the write-lock
-            let _ = write_lock.entry(*client).or_default();
+        transaction: &Transaction,
+        owner: ClientId,
+    ) -> Result<(), LedgerError> {
+        match transaction {
+            Transaction::Deposit { client, .. } if *client == owner => Ok(()),
+            _ => Err(LedgerError::DuplicateTransactionId(
+                transaction.get_transaction_id(),
+            )),
+        }
+    }
 
-            // Downgrade the write-lock to a read-lock and return
-            RwLockReadGuard::map(
-                RwLockWriteGuard::downgrade(write_lock),
-                |hm: &HashMap<ClientId, Mutex<Wallet>>| hm.get(client).unwrap(),
-            )
+    fn apply_to_wallet(
+        &self,
+        wallet: &mut Wallet,
+        transaction: Transaction,
+    ) -> Result<(), WalletError> {
+        match transaction {
+            Transaction::Deposit { tx, amount, .. } => wallet.deposit(tx, amount),
+            Transaction::Withdrawal { tx, amount, .. } => wallet.withdraw(tx, amount),
+            Transaction::Dispute { tx, .. } => wallet.dispute(tx),
+            // the amount wasn't validated while parsing, `dispute_partial` checks it's
+            // positive and doesn't exceed the deposit
+            Transaction::PartialDispute { tx, amount, .. } => {
+                if self.config.partial_disputes {
+                    wallet.dispute_partial(tx, amount)
+                } else {
+                    wallet.dispute(tx)
+                }
+            }
+            Transaction::Resolve { tx, .. } => wallet.resolve(tx),
+            Transaction::Chargeback { tx, .. } => wallet.chargeback(tx),
+            Transaction::Freeze { .. } => {
+                wallet.freeze();
+                Ok(())
+            }
+            // failing queued deposits don't fail the unfreeze, see `drop_queued_deposits`
+            Transaction::Unfreeze { .. } => wallet.unfreeze().map(|_| ()),
         }
     }
 
-    fn get_existing_client(
+    /// Checks whether `transaction` would be applied, without changing the ledger:
+    /// it's applied to a copy of the client's wallet, taken under the wallet's lock.
+    pub fn validate_transaction(&self, transaction: &Transaction) -> Result<(), LedgerError> {
+        if self.is_filtered_out(transaction) {
+            return Ok(());
+        }
+        let client = transaction.get_client_id();
+        let mut wallet = match self.get_existing_client(&client) {
+            Some(wallet) => wallet.lock().clone(),
+            None if matches!(transaction, Transaction::Deposit { .. }) => {
+                Wallet::new(self.config.clone())
+            }
+            None => return Err(LedgerError::InexistentClient(client)),
+        };
+        let tx = transaction.get_transaction_id();
+        match transaction {
+            Transaction::Deposit { .. } | Transaction::Withdrawal { .. } => {
+                if let Some(owner) = self.transactions.owner(client, tx) {
+                    self.check_duplicate(transaction, owner)?;
+                }
+            }
+            Transaction::Freeze { .. } | Transaction::Unfreeze { .. } => {}
+            _ => self.transactions.check_owner(client, tx)?,
+        }
+        Ok(self.apply_to_wallet(&mut wallet, transaction.clone())?)
+    }
+
+    fn is_filtered_out(&self, transaction: &Transaction) -> bool {
+        self.config
+            .type_filter
+            .as_ref()
+            .is_some_and(|type_filter| !type_filter.contains(&transaction.get_type()))
+    }
+
+    fn get_existing_or_create_client(&self, client: &ClientId) -> S::Guard<'_> {
+        self.clients
+            .get_or_create(client, || Wallet::new(self.config.clone()))
+    }
+
+    fn get_existing_client(&self, client: &ClientId) -> Option<S::Guard<'_>> {
+        self.clients.get(client)
+    }
+
+    /// Locks the client's wallet once and lets `f` apply several operations to it.
+    /// Only existing clients can be accessed this way.
+    pub fn with_client_locked<F, R>(&self, client: ClientId, f: F) -> Result<R, LedgerError>
+    where
+        F: FnOnce(&mut Wallet) -> R,
+    {
+        let wallet = self
+            .get_existing_client(&client)
+            .ok_or(LedgerError::InexistentClient(client))?;
+        let mut wallet = wallet.lock();
+        Ok(f(&mut wallet))
+    }
+
+    /// Lifts a manual freeze of the client's account like a `Transaction::Unfreeze`, applying
+    /// any deposits queued while it was frozen. Returns the queued deposits that failed and
+    /// got dropped. An account locked by a chargeback stays locked, see `force_unlock`.
+    pub fn unfreeze(
+        &self,
+        client: ClientId,
+    ) -> Result<Vec<(TransactionId, WalletError)>, LedgerError> {
+        let failed = self
+            .get_existing_client(&client)
+            .ok_or(LedgerError::InexistentClient(client))?
+            .lock()
+            .unfreeze()?;
+        self.drop_queued_deposits(client, &failed);
+        Ok(failed)
+    }
+
+    /// Operator override unlocking the client's account whatever locked it, chargebacks
+    /// included, and applying any queued deposits like `unfreeze`.
+    pub fn force_unlock(
         &self,
-        client: &ClientId,
-    ) -> Option<MappedRwLockReadGuard<Mutex<Wallet>>> {
-        let read_lock = self.clients.read();
+        client: ClientId,
+    ) -> Result<Vec<(TransactionId, WalletError)>, LedgerError> {
+        let failed = self
+            .get_existing_client(&client)
+            .ok_or(LedgerError::InexistentClient(client))?
+            .lock()
+            .unlock();
+        self.drop_queued_deposits(client, &failed);
+        Ok(failed)
+    }
 
-        RwLockReadGuard::try_map(read_lock, |hm| hm.get(client)).ok()
+    /// Gives back the tx ids of queued deposits that failed when the account got unlocked,
+    /// so they can be reused like those of any rejected deposit
+    fn drop_queued_deposits(&self, client: ClientId, failed: &[(TransactionId, WalletError)]) {
+        for (tx, e) in failed {
+            warn!(client, tx, "Queued deposit dropped on unlock: {e}");
+            self.transactions.release(client, *tx);
+        }
+    }
+
+    pub fn metrics(&self) -> &LedgerMetrics {
+        &self.metrics
+    }
+
+    /// Writes the transaction counters and the `health()` gauges in the Prometheus
+    /// text exposition format, e.g. to serve a `/metrics` endpoint.
+    pub fn write_prometheus_metrics<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        self.metrics.write_prometheus(w)?;
+        let health = self.health();
+        for (name, help, value) in [
+            (
+                "payments_clients",
+                "Number of clients",
+                health.clients.to_string(),
+            ),
+            (
+                "payments_locked_clients",
+                "Number of locked clients",
+                health.locked.to_string(),
+            ),
+            (
+                "payments_held_total",
+                "Funds held by open disputes over all clients",
+                health.total_held.to_string(),
+            ),
+        ] {
+            writeln!(w, "# HELP {name} {help}")?;
+            writeln!(w, "# TYPE {name} gauge")?;
+            writeln!(w, "{name} {value}")?;
+        }
+        w.flush()?;
+        Ok(())
+    }
+
+    pub fn health(&self) -> EngineHealth {
+        let mut health = EngineHealth {
+            clients: 0,
+            locked: 0,
+            total_held: Decimal::ZERO,
+        };
+        self.clients.for_each(|_, wallet| {
+            let (_, held, locked) = wallet.lock().balance_snapshot();
+            health.clients += 1;
+            if locked {
+                health.locked += 1;
+            }
+            health.total_held += held;
+        });
+        health
+    }
+
+    /// Number of clients, counted under the store's read lock
+    pub fn client_count(&self) -> usize {
+        self.clients.len()
+    }
+
+    /// Rough estimate of the memory held by the wallets, their deposit logs and the tx id
+    /// registry, from `size_of` and map capacities. Allocator overhead isn't accounted for,
+    /// so only use it to compare ledgers or to decide when to shard.
+    pub fn approximate_memory_bytes(&self) -> usize {
+        let mut bytes = std::mem::size_of::<Self>() + self.transactions.approximate_memory_bytes();
+        self.clients.for_each(|_, wallet| {
+            bytes += std::mem::size_of::<(ClientId, Mutex<Wallet>)>()
+                + wallet.lock().approximate_heap_bytes();
+        });
+        bytes
+    }
+
+    /// Sums the balances of every client, each read under its wallet's lock
+    pub fn aggregate(&self) -> LedgerAggregate {
+        let mut aggregate = LedgerAggregate::default();
+        self.clients.for_each(|_, wallet| {
+            let (available, held, locked) = wallet.lock().balance_snapshot();
+            aggregate.clients += 1;
+            aggregate.available += available;
+            aggregate.held += held;
+            aggregate.total += available + held;
+            if locked {
+                aggregate.locked += 1;
+            }
+        });
+        aggregate.available = aggregate.available.normalize();
+        aggregate.held = aggregate.held.normalize();
+        aggregate.total = aggregate.total.normalize();
+        aggregate
+    }
+
+    /// Writes the `aggregate` as a header and a single row, in the format of the dump
+    pub fn write_aggregate<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let aggregate = self.aggregate();
+        writeln!(w, "clients, available, held, total, locked")?;
+        writeln!(
+            w,
+            "{}, {}, {}, {}, {}",
+            aggregate.clients,
+            aggregate.available,
+            aggregate.held,
+            aggregate.total,
+            aggregate.locked
+        )?;
+        Ok(w.flush()?)
+    }
+
+    /// Clients with at least one deposit currently `Disputed`, ordered by id
+    pub fn clients_with_open_disputes(&self) -> Vec<ClientId> {
+        let mut clients = Vec::new();
+        self.clients.for_each(|client_id, wallet| {
+            if wallet.lock().has_open_disputes() {
+                clients.push(client_id);
+            }
+        });
+        clients.sort_unstable();
+        clients
+    }
+
+    /// Balances of a single client, captured under its wallet lock.
+    /// `None` if the client doesn't exist.
+    pub fn get_balance(&self, client: ClientId) -> Option<WalletSnapshot> {
+        self.get_existing_client(&client)
+            .map(|wallet| wallet.lock().snapshot())
+    }
+
+    /// Every client's balances, ordered by id. Each snapshot is taken under its wallet lock,
+    /// but the wallets are locked one after the other: transactions applied meanwhile to
+    /// clients already visited aren't reflected, so the result isn't a global point in time.
+    pub fn iter_snapshots(&self) -> Vec<(ClientId, WalletSnapshot)> {
+        self.snapshot().clients.into_iter().collect()
+    }
+
+    /// Writes the wallets, with the status of every deposit, in a binary format that
+    /// `load_snapshot` restores. Each wallet is copied under its own lock, so
+    /// transactions processed meanwhile may be partially included.
+    pub fn save_snapshot<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let mut wallets = Vec::with_capacity(self.clients.len());
+        self.clients.for_each(|client_id, wallet| {
+            wallets.push((client_id, wallet.lock().clone()));
+        });
+        wallets.sort_unstable_by_key(|(client_id, _)| *client_id);
+        let mut transactions = self.transactions.entries();
+        transactions.sort_unstable();
+        Checkpoint {
+            wallets,
+            transactions,
+        }
+        .serialize(w)?;
+        Ok(w.flush()?)
+    }
+
+    pub fn snapshot(&self) -> LedgerSnapshot {
+        let mut snapshot = LedgerSnapshot::default();
+        self.clients.for_each(|client_id, wallet| {
+            snapshot.clients.insert(client_id, wallet.lock().snapshot());
+        });
+        snapshot
+    }
+
+    /// Writes a plain text report meant to be read by an operator: the balances and lock
+    /// reason of every client, the open disputes and the totals over all clients.
+    pub fn write_report<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let mut clients = Vec::with_capacity(self.clients.len());
+        self.clients.for_each(|client_id, wallet| {
+            let wallet = wallet.lock();
+            clients.push((
+                client_id,
+                wallet.snapshot(),
+                wallet.get_lock_reason(),
+                wallet.open_disputes(),
+            ));
+        });
+        clients.sort_unstable_by_key(|(client_id, ..)| *client_id);
+
+        writeln!(w, "== Clients ==")?;
+        for (client_id, snapshot, lock_reason, _) in clients.iter() {
+            let status = match lock_reason {
+                Some(reason) => format!("locked ({reason})"),
+                None => "unlocked".to_string(),
+            };
+            writeln!(
+                w,
+                "client {client_id}: available {}, held {}, total {}, {status}",
+                snapshot.available, snapshot.held, snapshot.total
+            )?;
+        }
+
+        writeln!(w, "== Open disputes ==")?;
+        for (client_id, _, _, disputes) in clients.iter() {
+            for (tx, amount) in disputes {
+                writeln!(w, "client {client_id}: tx {tx} ({amount} held)")?;
+            }
+        }
+
+        let (mut available, mut held, mut locked) = (Decimal::ZERO, Decimal::ZERO, 0);
+        for (_, snapshot, ..) in clients.iter() {
+            available += snapshot.available;
+            held += snapshot.held;
+            locked += usize::from(snapshot.locked);
+        }
+        writeln!(w, "== Totals ==")?;
+        writeln!(
+            w,
+            "clients: {}, locked: {locked}, available: {available}, held: {held}, total: {}",
+            clients.len(),
+            available + held
+        )?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// Writes the dispute register: one `client, tx, amount, status` row for every deposit
+    /// that was ever disputed, ordered by client then tx.
+    pub fn export_disputes_to_writer<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let mut clients = Vec::new();
+        self.clients.for_each(|client_id, wallet| {
+            let disputes = wallet.lock().disputed_deposits();
+            if !disputes.is_empty() {
+                clients.push((client_id, disputes));
+            }
+        });
+        clients.sort_unstable_by_key(|(client_id, _)| *client_id);
+
+        writeln!(w, "client, tx, amount, status")?;
+        for (client_id, disputes) in clients {
+            for (tx, amount, status) in disputes {
+                writeln!(w, "{client_id}, {tx}, {amount}, {status}")?;
+            }
+        }
+        w.flush()?;
+        Ok(())
     }
 
     pub fn dump_to_writer<W>(&self, w: &mut W) -> Result<(), LedgerError>
     where
         W: std::io::Write,
     {
-        let map = self.clients.read();
-        w.write_all("client, available, held, total, locked\n".as_bytes())
+        self.dump_to_writer_with_options(w, &DumpOptions::default())
+    }
+
+    /// Like `dump_to_writer`, with an extra `dispute_count` column
+    pub fn dump_to_writer_extended<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let mut columns = OutputColumns::default();
+        columns.0.push(OutputColumn::DisputeCount);
+        let options = DumpOptions {
+            columns,
+            ..Default::default()
+        };
+        self.dump_to_writer_with_options(w, &options)
+    }
+
+    /// Like `dump_to_writer`, with the rows ordered by ascending client id
+    pub fn dump_to_writer_sorted<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let options = DumpOptions {
+            order: OutputOrder::ByClientId,
+            ..Default::default()
+        };
+        self.dump_to_writer_with_options(w, &options)
+    }
+
+    /// Writes every client as a JSON object in a JSON array, with the rows captured the
+    /// same way as `dump_to_writer_with_options`.
+    pub fn dump_json_to_writer<W>(&self, w: &mut W) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        let mut rows = Vec::with_capacity(self.clients.len());
+        self.clients.for_each(|client_id, wallet| {
+            rows.push(JsonRow::new(client_id, &wallet.lock().snapshot()));
+        });
+        serde_json::to_writer(&mut *w, &rows).map_err(std::io::Error::from)?;
+        w.flush()?;
+        Ok(())
+    }
+
+    /// The rows are captured before anything is written, while the store is iterated
+    /// under its read-lock: clients created during the dump (even by a slow writer) are
+    /// left out, and transactions applied meanwhile don't show up in the output.
+    pub fn dump_to_writer_with_options<W>(
+        &self,
+        w: &mut W,
+        options: &DumpOptions,
+    ) -> Result<(), LedgerError>
+    where
+        W: std::io::Write,
+    {
+        // Snapshot every row first, see above
+        let mut rows = Vec::with_capacity(self.clients.len());
+        self.clients.for_each(|client_id, wallet| {
+            rows.push((client_id, wallet.lock().snapshot()));
+        });
+        if options.collapse_duplicate_clients {
+            rows = collapse_duplicate_clients(rows);
+        }
+        options.order.sort(&mut rows);
+        if let Some(decimal_places) = options.decimal_places {
+            for (_, snapshot) in rows.iter_mut() {
+                *snapshot = with_decimal_places(*snapshot, decimal_places);
+            }
+        }
+
+        if options.single_write {
+            let mut output = options.columns.header();
+            for (client_id, snapshot) in rows.iter() {
+                output.push_str(&options.columns.row(*client_id, snapshot));
+            }
+            if options.omit_trailing_newline {
+                output.pop();
+            }
+            w.write_all(output.as_bytes())?;
+        } else {
+            let mut header = options.columns.header();
+            if rows.is_empty() && options.omit_trailing_newline {
+                header.pop();
+            }
+            w.write_all(header.as_bytes())?;
+            for (i, (client_id, snapshot)) in rows.iter().enumerate() {
+                let mut row = options.columns.row(*client_id, snapshot);
+                if i + 1 == rows.len() && options.omit_trailing_newline {
+                    row.pop();
+                }
+                w.write_all(row.as_bytes())?;
+                if options.flush_each_row {
+                    w.flush()?;
+                }
+            }
+        }
+        w.flush()?;
+        Ok(())
+    }
+}
+
+/// Deep copy, each wallet is copied under its own lock
+impl<S: WalletStore> Clone for Ledger<S> {
+    fn clone(&self) -> Self {
+        let clients = S::default();
+        self.clients.for_each(|client_id, wallet| {
+            let wallet = wallet.lock().clone();
+            clients.get_or_create(&client_id, || wallet);
+        });
+        Ledger {
+            clients,
+            config: self.config.clone(),
+            metrics: self.metrics.clone(),
+            dispute_aging: self.dispute_aging.clone(),
+            transactions: self.transactions.clone(),
+            apply_hook: self.apply_hook.clone(),
+        }
+    }
+}
+
+/// Deep comparison of the clients, their balances, locked status and deposit logs.
+/// The config, the metrics, the claimed tx ids, the queued deposits and the per-wallet
+/// counters are left out.
+impl<S: WalletStore> PartialEq for Ledger<S> {
+    fn eq(&self, other: &Self) -> bool {
+        if std::ptr::eq(self, other) {
+            return true;
+        }
+        // Copy `self`'s wallets before reading `other`, so no lock of one ledger is held while
+        // waiting on the other: a fair `RwLock` makes new readers wait behind a queued writer,
+        // so two threads comparing the same ledgers in opposite order could deadlock
+        let mut wallets = Vec::new();
+        self.clients.for_each(|client_id, wallet| {
+            wallets.push((client_id, wallet.lock().clone()));
+        });
+        wallets.len() == other.clients.len()
+            && wallets.iter().all(|(client_id, wallet)| {
+                other
+                    .clients
+                    .get(client_id)
+                    .is_some_and(|other_wallet| other_wallet.lock().same_state(wallet))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use parking_lot::{MappedRwLockReadGuard, RwLockReadGuard, RwLockWriteGuard};
+
+    use super::*;
+    use crate::domain::{
+        namespaced_client_id, OutputColumn, OutputColumns, OutputOrder, RejectionKind,
+    };
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_health_reflects_locked_clients_and_held_funds() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(5),
+            },
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: dec!(1),
+            },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        let expected = EngineHealth {
+            clients: 3,
+            locked: 1,
+            total_held: dec!(5),
+        };
+        assert_eq!(ledger.health(), expected);
+    }
+
+    #[test]
+    fn test_concurrent_deposits_to_new_client_create_one_wallet_and_lose_nothing() {
+        const THREADS: u32 = 16;
+        const DEPOSITS_PER_THREAD: u32 = 100;
+        let ledger = Ledger::new();
+        let barrier = std::sync::Barrier::new(THREADS as usize);
+
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let ledger = &ledger;
+                let barrier = &barrier;
+                scope.spawn(move || {
+                    // start together so the first deposits race to create the client
+                    barrier.wait();
+                    for i in 0..DEPOSITS_PER_THREAD {
+                        ledger
+                            .process_transaction(Transaction::Deposit {
+                                client: 7,
+                                tx: thread * DEPOSITS_PER_THREAD + i,
+                                amount: dec!(1),
+                            })
+                            .unwrap();
+                    }
+                });
+            }
+        });
+
+        let deposits = THREADS * DEPOSITS_PER_THREAD;
+        assert_eq!(ledger.health().clients, 1);
+        let snapshot = ledger.snapshot().clients[&7];
+        assert_eq!(snapshot.total, Decimal::from(deposits));
+        assert_eq!(snapshot.tx_count, u64::from(deposits));
+    }
+
+    #[test]
+    fn test_stress_distinct_clients_processed_concurrently_keep_exact_balances() {
+        const ITERATIONS: u32 = 50;
+        const THREADS: u32 = 8;
+        const CLIENTS_PER_THREAD: u32 = 4;
+        const ROUNDS: u32 = 25;
+
+        for _ in 0..ITERATIONS {
+            let ledger = Ledger::new();
+            let barrier = std::sync::Barrier::new(THREADS as usize + 1);
+            std::thread::scope(|scope| {
+                for thread in 0..THREADS {
+                    let (ledger, barrier) = (&ledger, &barrier);
+                    scope.spawn(move || {
+                        barrier.wait();
+                        let clients =
+                            thread * CLIENTS_PER_THREAD..(thread + 1) * CLIENTS_PER_THREAD;
+                        for round in 0..ROUNDS {
+                            for client in clients.clone() {
+                                // tx ids are unique over all clients
+                                let tx = (client * ROUNDS + round) * 3;
+                                let results = ledger.process_batch([
+                                    Transaction::Deposit {
+                                        client,
+                                        tx,
+                                        amount: dec!(2),
+                                    },
+                                    Transaction::Withdrawal {
+                                        client,
+                                        tx: tx + 1,
+                                        amount: dec!(0.5),
+                                    },
+                                    Transaction::Dispute { client, tx },
+                                    Transaction::Resolve { client, tx },
+                                ]);
+                                assert!(results.iter().all(Result::is_ok), "{results:?}");
+                            }
+                        }
+                    });
+                }
+                // readers iterating the store while wallets get created and mutated
+                scope.spawn(|| {
+                    barrier.wait();
+                    for _ in 0..ROUNDS {
+                        let aggregate = ledger.aggregate();
+                        assert_eq!(aggregate.total, aggregate.available + aggregate.held);
+                    }
+                });
+            });
+
+            assert_eq!(
+                ledger.client_count(),
+                (THREADS * CLIENTS_PER_THREAD) as usize
+            );
+            for (_, balance) in ledger.iter_snapshots() {
+                assert_eq!(balance.available, Decimal::from(ROUNDS) * dec!(1.5));
+                assert_eq!(balance.held, dec!(0));
+                assert_eq!(balance.tx_count, u64::from(ROUNDS) * 2);
+            }
+        }
+    }
+
+    #[test]
+    fn test_locked_wallet_doesnt_block_other_existing_clients() {
+        let ledger = Ledger::new();
+        for client in [1, 2] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount: dec!(1),
+                })
+                .unwrap();
+        }
+        let (locked_tx, locked_rx) = std::sync::mpsc::channel();
+        let (done_tx, done_rx) = std::sync::mpsc::channel();
+
+        std::thread::scope(|scope| {
+            let ledger = &ledger;
+            let holder = scope.spawn(move || {
+                ledger
+                    .with_client_locked(1, |_| {
+                        locked_tx.send(()).unwrap();
+                        // hold client 1 until client 2 got processed, or give up
+                        // (failing the test) if client 2 is blocked by it
+                        done_rx.recv_timeout(std::time::Duration::from_secs(5))
+                    })
+                    .unwrap()
+            });
+            locked_rx.recv().unwrap();
+            ledger
+                .process_transaction(Transaction::Withdrawal {
+                    client: 2,
+                    tx: 3,
+                    amount: dec!(1),
+                })
+                .unwrap();
+            let _ = done_tx.send(());
+            assert!(holder.join().unwrap().is_ok());
+        });
+
+        assert_eq!(ledger.get_balance(2).unwrap().available, dec!(0));
+    }
+
+    #[test]
+    fn test_get_balance_is_consistent_with_concurrent_deposits() {
+        const THREADS: u32 = 8;
+        const DEPOSITS_PER_THREAD: u32 = 50;
+        let ledger = Ledger::new();
+        assert_eq!(ledger.get_balance(3), None);
+
+        std::thread::scope(|scope| {
+            for thread in 0..THREADS {
+                let ledger = &ledger;
+                scope.spawn(move || {
+                    for i in 0..DEPOSITS_PER_THREAD {
+                        ledger
+                            .process_transaction(Transaction::Deposit {
+                                client: 3,
+                                tx: thread * DEPOSITS_PER_THREAD + i,
+                                amount: dec!(0.5),
+                            })
+                            .unwrap();
+                        let balance = ledger.get_balance(3).unwrap();
+                        assert_eq!(balance.total, balance.available + balance.held);
+                    }
+                });
+            }
+        });
+
+        let balance = ledger.get_balance(3).unwrap();
+        assert_eq!(
+            balance.total,
+            Decimal::from(THREADS * DEPOSITS_PER_THREAD) / dec!(2)
+        );
+        assert_eq!(balance.held, dec!(0));
+        assert!(!balance.locked);
+        assert_eq!(ledger.get_balance(4), None);
+    }
+
+    #[test]
+    fn test_dispute_of_another_clients_tx_is_a_client_mismatch() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(5),
+            },
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: dec!(5),
+            },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        for transaction in [
+            Transaction::Dispute { client: 2, tx: 1 },
+            Transaction::Chargeback { client: 3, tx: 1 },
+        ] {
+            let actual = transaction.get_client_id();
+            assert!(matches!(
+                ledger.validate_transaction(&transaction),
+                Err(LedgerError::ClientMismatch { tx: 1, expected: 1, actual: a }) if a == actual
+            ));
+            assert!(matches!(
+                ledger.process_transaction(transaction),
+                Err(LedgerError::ClientMismatch { tx: 1, expected: 1, actual: a }) if a == actual
+            ));
+        }
+        // tx ids are only registered per namespace
+        let other_tenant = namespaced_client_id(1, 2);
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Dispute {
+                client: other_tenant,
+                tx: 1
+            }),
+            Err(LedgerError::InexistentClient(c)) if c == other_tenant
+        ));
+        assert_eq!(ledger.health().total_held, dec!(0));
+    }
+
+    #[test]
+    fn test_apply_hook_fires_for_applied_transactions_only() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let ledger = Ledger::new().with_apply_hook({
+            let calls = calls.clone();
+            move |transaction: &Transaction, snapshot: &WalletSnapshot| {
+                calls.lock().push((transaction.clone(), *snapshot))
+            }
+        });
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(10),
+        };
+        let dispute = Transaction::Dispute { client: 1, tx: 1 };
+        let chargeback = Transaction::Chargeback { client: 1, tx: 1 };
+        ledger.process_batch([
+            deposit.clone(),
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(20),
+            },
+            dispute.clone(),
+            Transaction::Resolve { client: 2, tx: 1 },
+            chargeback.clone(),
+        ]);
+
+        let snapshot = |available, held, locked| WalletSnapshot {
+            available,
+            held,
+            total: available + held,
+            locked,
+            tx_count: 1,
+            dispute_count: u64::from(held > dec!(0) || locked),
+        };
+        assert_eq!(
+            *calls.lock(),
+            [
+                (deposit, snapshot(dec!(10), dec!(0), false)),
+                (dispute, snapshot(dec!(0), dec!(10), false)),
+                (chargeback, snapshot(dec!(0), dec!(0), true)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_saved_snapshot_restores_disputes_and_keeps_processing() {
+        let ledger = Ledger::new();
+        ledger.process_batch([
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10.5),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(3),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: dec!(4),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Chargeback { client: 2, tx: 3 },
+        ]);
+
+        let mut saved = Vec::new();
+        ledger.save_snapshot(&mut saved).unwrap();
+        let restored = Ledger::load_snapshot(&mut saved.as_slice()).unwrap();
+        assert_eq!(restored, ledger);
+
+        restored
+            .process_transaction(Transaction::Chargeback { client: 1, tx: 1 })
             .unwrap();
-        for (client_id, wallet) in map.iter() {
-            let wallet = wallet.lock();
-            w.write_all(
-                format!(
-                    "{}, {}, {}, {}, {}\n",
+        let balance = restored.get_balance(1).unwrap();
+        assert_eq!((balance.available, balance.held), (dec!(3), dec!(0)));
+        assert!(balance.locked);
+        assert!(matches!(
+            restored.process_transaction(Transaction::Deposit {
+                client: 3,
+                tx: 2,
+                amount: dec!(1),
+            }),
+            Err(LedgerError::DuplicateTransactionId(2))
+        ));
+        assert!(matches!(
+            Ledger::load_snapshot(&mut &saved[..saved.len() - 1]),
+            Err(LedgerError::IoError(_))
+        ));
+    }
+
+    #[test]
+    fn test_aggregate_sums_balances_over_all_clients() {
+        let ledger = Ledger::new();
+        ledger.process_batch([
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10.5),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(4),
+            },
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: dec!(1),
+            },
+            Transaction::Dispute { client: 3, tx: 3 },
+            Transaction::Chargeback { client: 3, tx: 3 },
+        ]);
+
+        let expected = LedgerAggregate {
+            clients: 3,
+            available: dec!(10.5),
+            held: dec!(4),
+            total: dec!(14.5),
+            locked: 1,
+        };
+        assert_eq!(ledger.aggregate(), expected);
+        let mut output = Vec::new();
+        ledger.write_aggregate(&mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "clients, available, held, total, locked\n3, 10.5, 4, 14.5, 1\n"
+        );
+    }
+
+    #[test]
+    fn test_locked_account_rejects_deposits_unless_allowed_by_policy() {
+        let lock = [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(4),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+        ];
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 3,
+            amount: dec!(5),
+        };
+
+        let ledger = Ledger::new_with_policy(LockPolicy::RejectAll);
+        assert!(ledger.process_batch(lock.clone()).iter().all(Result::is_ok));
+        assert!(matches!(
+            ledger.process_transaction(deposit.clone()),
+            Err(LedgerError::WalletError(WalletError::AccountLocked))
+        ));
+        assert_eq!(ledger.get_balance(1).unwrap().available, dec!(4));
+
+        let ledger = Ledger::new_with_policy(LockPolicy::AllowDeposits);
+        assert!(ledger.process_batch(lock).iter().all(Result::is_ok));
+        ledger.process_transaction(deposit).unwrap();
+        let balance = ledger.get_balance(1).unwrap();
+        assert_eq!((balance.available, balance.locked), (dec!(9), true));
+        for transaction in [
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 4,
+                amount: dec!(1),
+            },
+            Transaction::Dispute { client: 1, tx: 3 },
+        ] {
+            assert!(matches!(
+                ledger.process_transaction(transaction),
+                Err(LedgerError::WalletError(WalletError::AccountLocked))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_client_count_counts_clients_created_by_deposits() {
+        let ledger = Ledger::new();
+        assert_eq!(ledger.client_count(), 0);
+        let empty_bytes = ledger.approximate_memory_bytes();
+
+        for (client, tx) in [(1, 1), (2, 2), (1, 3), (3, 4)] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx,
+                    amount: dec!(1),
+                })
+                .unwrap();
+        }
+        assert!(ledger
+            .process_transaction(Transaction::Withdrawal {
+                client: 4,
+                tx: 5,
+                amount: dec!(1),
+            })
+            .is_err());
+
+        assert_eq!(ledger.client_count(), 3);
+        assert!(
+            ledger.approximate_memory_bytes()
+                >= empty_bytes + 3 * std::mem::size_of::<(ClientId, Mutex<Wallet>)>()
+        );
+    }
+
+    #[test]
+    fn test_dump_starts_with_the_csv_header() {
+        let ledger = Ledger::new();
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(2.5),
+            })
+            .unwrap();
+        let mut output = Vec::new();
+        ledger.dump_to_writer(&mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let (header, row) = output.split_at(Ledger::CSV_HEADER.len());
+        assert_eq!(header, Ledger::CSV_HEADER);
+        assert_eq!(row, ledger.get_balance(1).unwrap().to_csv_row(1));
+    }
+
+    #[test]
+    fn test_unfreeze_after_chargeback_keeps_the_account_locked() {
+        let ledger = Ledger::new();
+        let results = ledger.process_batch([
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Freeze { client: 1, tx: 2 },
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+
+        assert!(matches!(
+            ledger.process_transaction(Transaction::Unfreeze { client: 1, tx: 3 }),
+            Err(LedgerError::WalletError(WalletError::AccountLocked))
+        ));
+        assert!(matches!(
+            ledger.unfreeze(1),
+            Err(LedgerError::WalletError(WalletError::AccountLocked))
+        ));
+        assert!(ledger.get_balance(1).unwrap().locked);
+    }
+
+    #[test]
+    fn test_force_unlock_lifts_a_chargeback_lock() {
+        let ledger = Ledger::new();
+        let results = ledger.process_batch([
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(5),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+
+        assert_eq!(ledger.force_unlock(1).unwrap(), []);
+
+        assert!(!ledger.get_balance(1).unwrap().locked);
+        ledger
+            .process_transaction(Transaction::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: dec!(5),
+            })
+            .unwrap();
+        assert!(matches!(
+            ledger.force_unlock(2),
+            Err(LedgerError::InexistentClient(2))
+        ));
+    }
+
+    #[test]
+    fn test_failed_queued_deposits_release_their_tx_ids_on_force_unlock() {
+        let ledger = Ledger::with_config(EngineConfig {
+            queue_locked_deposits: true,
+            max_client_balance: Some(dec!(10)),
+            ..Default::default()
+        });
+        let results = ledger.process_batch([
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(4),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(11),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 3,
+                amount: dec!(2),
+            },
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+
+        let failed = ledger.force_unlock(1).unwrap();
+
+        assert_eq!(failed, [(2, WalletError::BalanceCeilingExceeded)]);
+        assert_eq!(ledger.get_balance(1).unwrap().available, dec!(2));
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(1),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_deposits_below_minimum_are_rejected_without_creating_the_client() {
+        let ledger = Ledger::with_config(EngineConfig {
+            min_deposit: Some(dec!(1)),
+            ..Default::default()
+        });
+        let below_minimum = |client, tx| Transaction::Deposit {
+            client,
+            tx,
+            amount: dec!(0.9999),
+        };
+
+        assert!(matches!(
+            ledger.process_transaction(below_minimum(1, 1)),
+            Err(LedgerError::WalletError(WalletError::DepositBelowMinimum(amount)))
+                if amount == dec!(0.9999)
+        ));
+        assert_eq!(ledger.client_count(), 0);
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(1),
+            })
+            .unwrap();
+        assert!(matches!(
+            ledger.with_client_locked(1, |wallet| wallet.deposit(2, dec!(0.5))),
+            Ok(Err(WalletError::DepositBelowMinimum(_)))
+        ));
+        assert!(ledger.validate_transaction(&below_minimum(1, 3)).is_err());
+        assert_eq!(ledger.get_balance(1).unwrap().available, dec!(1));
+        assert_eq!(
+            ledger
+                .metrics()
+                .rejections(RejectionKind::DepositBelowMinimum),
+            1
+        );
+    }
+
+    #[test]
+    fn test_non_positive_amounts_are_rejected_without_touching_the_balances() {
+        let ledger = Ledger::new();
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            })
+            .unwrap();
+        let before = ledger.get_balance(1).unwrap();
+
+        let results = ledger.process_batch([
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(-5),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: dec!(0),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 4,
+                amount: dec!(-5),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 5,
+                amount: dec!(-5),
+            },
+        ]);
+
+        for (result, amount) in results
+            .into_iter()
+            .zip([dec!(-5), dec!(0), dec!(-5), dec!(-5)])
+        {
+            assert!(matches!(
+                result,
+                Err(LedgerError::WalletError(WalletError::InvalidAmount(a))) if a == amount
+            ));
+        }
+        assert_eq!(ledger.get_balance(1).unwrap(), before);
+        assert_eq!(ledger.client_count(), 1);
+        assert_eq!(ledger.metrics().rejections(RejectionKind::InvalidAmount), 4);
+        // the rejected tx ids weren't claimed
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 4,
+                amount: dec!(1),
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn test_process_batch_returns_results_in_order() {
+        let ledger = Ledger::new();
+        let results = ledger.process_batch(vec![
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 2 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 1,
+                tx: 3,
+                amount: dec!(1),
+            },
+        ]);
+
+        assert_eq!(results.len(), 5);
+        assert!(results[..2].iter().all(Result::is_ok));
+        assert!(matches!(
+            results[2],
+            Err(LedgerError::WalletError(WalletError::InexistentTransaction))
+        ));
+        assert!(results[3].is_ok());
+        assert!(matches!(
+            results[4],
+            Err(LedgerError::WalletError(WalletError::AccountLocked))
+        ));
+        let balance = ledger.get_balance(1).unwrap();
+        assert_eq!((balance.total, balance.locked), (dec!(0), true));
+    }
+
+    #[test]
+    fn test_tx_id_reused_by_another_client_is_a_duplicate() {
+        let ledger = Ledger::new();
+        ledger
+            .process_transaction(Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            })
+            .unwrap();
+
+        for transaction in [
+            Transaction::Deposit {
+                client: 2,
+                tx: 1,
+                amount: dec!(5),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 1,
+                amount: dec!(5),
+            },
+        ] {
+            assert!(matches!(
+                ledger.validate_transaction(&transaction),
+                Err(LedgerError::DuplicateTransactionId(1))
+            ));
+            assert!(matches!(
+                ledger.process_transaction(transaction),
+                Err(LedgerError::DuplicateTransactionId(1))
+            ));
+        }
+        assert!(ledger.get_balance(2).is_none());
+
+        // A rejected transaction doesn't use up its tx id
+        assert!(ledger
+            .process_transaction(Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(50),
+            })
+            .is_err());
+        ledger
+            .process_transaction(Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(5),
+            })
+            .unwrap();
+        assert_eq!(ledger.get_balance(1).unwrap().available, dec!(5));
+    }
+
+    #[test]
+    fn test_dispute_register_lists_every_disputed_deposit_with_its_status() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 2,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(4),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 3,
+                amount: dec!(6),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 4,
+                amount: dec!(1),
+            },
+            Transaction::Dispute { client: 2, tx: 1 },
+            Transaction::Dispute { client: 1, tx: 3 },
+            Transaction::Dispute { client: 1, tx: 2 },
+            Transaction::Resolve { client: 1, tx: 3 },
+            Transaction::Chargeback { client: 2, tx: 1 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        let mut output = Vec::new();
+        ledger.export_disputes_to_writer(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, tx, amount, status\n\
+             1, 2, 4, disputed\n\
+             1, 3, 6, resolved\n\
+             2, 1, 10, chargedback\n"
+        );
+    }
+
+    #[test]
+    fn test_iter_snapshots_returns_every_client_ordered_by_id() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 3,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(4),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: dec!(6),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Withdrawal {
+                client: 3,
+                tx: 4,
+                amount: dec!(2.5),
+            },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        let snapshot = |available, held, tx_count, dispute_count| WalletSnapshot {
+            available,
+            held,
+            total: available + held,
+            locked: false,
+            tx_count,
+            dispute_count,
+        };
+        assert_eq!(
+            ledger.iter_snapshots(),
+            vec![
+                (1, snapshot(dec!(4), dec!(0), 1, 0)),
+                (2, snapshot(dec!(0), dec!(6), 1, 1)),
+                (3, snapshot(dec!(7.5), dec!(0), 2, 0)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_clients_with_open_disputes_skips_resolved_clients() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(10),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: dec!(5),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Resolve { client: 2, tx: 3 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        assert_eq!(ledger.clients_with_open_disputes(), [1]);
+    }
+
+    #[test]
+    fn test_report_lists_balances_disputes_lock_reasons_and_totals() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(5),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 2,
+                tx: 3,
+                amount: dec!(7),
+            },
+            Transaction::Dispute { client: 2, tx: 3 },
+            Transaction::Chargeback { client: 2, tx: 3 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        let mut output = Vec::new();
+        ledger.write_report(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "== Clients ==
+client 1: available 5, held 10, total 15, unlocked
+client 2: available 0, held 0, total 0, locked (chargeback of tx 3)
+== Open disputes ==
+client 1: tx 1 (10 held)
+== Totals ==
+clients: 2, locked: 1, available: 5, held: 10, total: 15
+"
+        );
+    }
+
+    #[test]
+    fn test_prometheus_metrics_count_transactions_and_report_gauges() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(2.5),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Chargeback { client: 2, tx: 2 },
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: dec!(1),
+            },
+        ] {
+            let _ = ledger.process_transaction(transaction);
+        }
+
+        let mut output = Vec::new();
+        ledger.write_prometheus_metrics(&mut output).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        let metrics: HashMap<_, _> = output
+            .lines()
+            .filter(|line| !line.starts_with('#'))
+            .map(|line| line.rsplit_once(' ').unwrap())
+            .collect();
+
+        assert_eq!(
+            metrics[r#"payments_transactions_total{type="deposit"}"#],
+            "2"
+        );
+        assert_eq!(
+            metrics[r#"payments_transactions_total{type="withdrawal"}"#],
+            "1"
+        );
+        assert_eq!(
+            metrics[r#"payments_transactions_total{type="dispute"}"#],
+            "2"
+        );
+        assert_eq!(
+            metrics[r#"payments_transactions_total{type="resolve"}"#],
+            "0"
+        );
+        assert_eq!(
+            metrics[r#"payments_transactions_total{type="chargeback"}"#],
+            "1"
+        );
+        // the withdrawal, all funds are held
+        assert_eq!(metrics["payments_transaction_errors_total"], "1");
+        assert_eq!(metrics["payments_clients"], "2");
+        assert_eq!(metrics["payments_locked_clients"], "1");
+        assert_eq!(metrics["payments_held_total"], "10");
+        assert!(output.contains("# TYPE payments_clients gauge"));
+    }
+
+    #[test]
+    fn test_metrics_count_transactions_rejections_and_skipped() {
+        let ledger = Ledger::with_config(EngineConfig {
+            type_filter: Some(HashSet::from([
+                TransactionType::Deposit,
+                TransactionType::Withdrawal,
+                TransactionType::Dispute,
+                TransactionType::Chargeback,
+            ])),
+            ..Default::default()
+        });
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(11),
+            },
+            Transaction::Withdrawal {
+                client: 2,
+                tx: 3,
+                amount: dec!(1),
+            },
+            Transaction::Dispute { client: 1, tx: 9 },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Chargeback { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 1,
+                tx: 4,
+                amount: dec!(1),
+            },
+        ] {
+            let _ = ledger.process_transaction(transaction);
+        }
+
+        let metrics = ledger.metrics();
+        assert_eq!(metrics.transactions(TransactionType::Deposit), 3);
+        assert_eq!(metrics.transactions(TransactionType::Withdrawal), 2);
+        assert_eq!(metrics.transactions(TransactionType::Dispute), 2);
+        assert_eq!(metrics.transactions(TransactionType::Resolve), 0);
+        assert_eq!(metrics.transactions(TransactionType::Chargeback), 2);
+        assert_eq!(metrics.skipped(), 1);
+        assert_eq!(metrics.errors(), 6);
+        for (kind, count) in [
+            (RejectionKind::DepositIdExists, 1),
+            (RejectionKind::InsufficientFunds, 1),
+            (RejectionKind::InexistentClient, 1),
+            (RejectionKind::InexistentTransaction, 1),
+            (RejectionKind::AccountLocked, 2),
+            (RejectionKind::Overflow, 0),
+        ] {
+            assert_eq!(metrics.rejections(kind), count, "{kind:?}");
+        }
+    }
+
+    #[test]
+    fn test_dispute_auto_resolves_after_window() {
+        let ledger = Ledger::with_config(EngineConfig {
+            auto_resolve_after: Some(2),
+            ..Default::default()
+        });
+        let deposit = |client, tx| Transaction::Deposit {
+            client,
+            tx,
+            amount: dec!(10),
+        };
+        for transaction in [
+            deposit(1, 1),
+            deposit(2, 2),
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Dispute { client: 2, tx: 2 },
+            deposit(3, 3),
+            // within the window of client 2's dispute
+            Transaction::Chargeback { client: 2, tx: 2 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+        // client 1's dispute was followed by 3 transactions
+        assert_eq!(ledger.health().total_held, dec!(0));
+        assert_eq!(ledger.snapshot().clients[&1].available, dec!(10));
+        assert!(ledger.snapshot().clients[&2].locked);
+        assert!(ledger
+            .process_transaction(Transaction::Chargeback { client: 1, tx: 1 })
+            .is_err());
+    }
+
+    #[test]
+    fn test_validate_transaction_predicts_outcome_without_mutating() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(1),
+            },
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Chargeback { client: 2, tx: 2 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+        let before = ledger.clone();
+
+        let would_succeed = [
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: dec!(10),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Deposit {
+                client: 3,
+                tx: 4,
+                amount: dec!(1),
+            },
+        ];
+        for transaction in would_succeed.iter() {
+            assert!(ledger.validate_transaction(transaction).is_ok());
+        }
+        assert!(matches!(
+            ledger.validate_transaction(&Transaction::Withdrawal {
+                client: 1,
+                tx: 3,
+                amount: dec!(10.01),
+            }),
+            Err(LedgerError::WalletError(WalletError::InsufficientFunds))
+        ));
+        assert!(matches!(
+            ledger.validate_transaction(&Transaction::Resolve { client: 4, tx: 1 }),
+            Err(LedgerError::InexistentClient(4))
+        ));
+        assert!(matches!(
+            ledger.validate_transaction(&Transaction::Deposit {
+                client: 2,
+                tx: 5,
+                amount: dec!(1),
+            }),
+            Err(LedgerError::WalletError(WalletError::AccountLocked))
+        ));
+        assert!(matches!(
+            ledger.validate_transaction(&Transaction::Resolve { client: 1, tx: 1 }),
+            Err(LedgerError::WalletError(WalletError::DepositLogError(_)))
+        ));
+        assert_eq!(ledger, before);
+    }
+
+    #[test]
+    fn test_dump_emits_custom_column_set() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(4),
+            },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+        let options = DumpOptions {
+            columns: OutputColumns(vec![
+                OutputColumn::Client,
+                OutputColumn::TxCount,
+                OutputColumn::Available,
+            ]),
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        ledger
+            .dump_to_writer_with_options(&mut output, &options)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, tx_count, available\n1, 2, 6\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_sorted_by_total_desc() {
+        let ledger = Ledger::new();
+        for (client, amount) in [(1, dec!(5)), (2, dec!(20)), (3, dec!(0.5)), (4, dec!(5))] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount,
+                })
+                .unwrap();
+        }
+        let options = DumpOptions {
+            columns: OutputColumns(vec![OutputColumn::Client, OutputColumn::Total]),
+            order: OutputOrder::ByTotalDesc,
+            ..Default::default()
+        };
+
+        let mut output = Vec::new();
+        ledger
+            .dump_to_writer_with_options(&mut output, &options)
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, total\n2, 20\n1, 5\n4, 5\n3, 0.5\n"
+        );
+    }
+
+    /// Records the output length at every flush
+    #[derive(Default)]
+    struct FlushRecorder {
+        output: Vec<u8>,
+        flushed_at: Vec<usize>,
+    }
+
+    impl std::io::Write for FlushRecorder {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.flushed_at.push(self.output.len());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dump_flushes_after_each_row_when_enabled() {
+        let ledger = Ledger::new();
+        for client in [1, 2] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount: dec!(1),
+                })
+                .unwrap();
+        }
+        let header_len = Ledger::CSV_HEADER.len();
+        let row_len = "1, 1, 0, 1, false\n".len();
+
+        let mut writer = FlushRecorder::default();
+        ledger.dump_to_writer(&mut writer).unwrap();
+        assert_eq!(writer.flushed_at, [header_len + 2 * row_len]);
+
+        let options = DumpOptions {
+            flush_each_row: true,
+            ..Default::default()
+        };
+        let mut writer = FlushRecorder::default();
+        ledger
+            .dump_to_writer_with_options(&mut writer, &options)
+            .unwrap();
+        assert_eq!(
+            writer.flushed_at,
+            [
+                header_len + row_len,
+                header_len + 2 * row_len,
+                header_len + 2 * row_len
+            ]
+        );
+    }
+
+    /// Counts the `write` calls
+    #[derive(Default)]
+    struct WriteCounter {
+        output: Vec<u8>,
+        writes: usize,
+    }
+
+    impl std::io::Write for WriteCounter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.writes += 1;
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Fails every write once `capacity` bytes have been written, like a closed pipe
+    struct FailingWriter {
+        capacity: usize,
+        written: usize,
+    }
+
+    impl std::io::Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.written >= self.capacity {
+                return Err(std::io::ErrorKind::BrokenPipe.into());
+            }
+            let len = buf.len().min(self.capacity - self.written);
+            self.written += len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dump_returns_write_errors_instead_of_panicking() {
+        let ledger = Ledger::new();
+        for client in [1, 2, 3] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount: dec!(1),
+                })
+                .unwrap();
+        }
+
+        for single_write in [false, true] {
+            let options = DumpOptions {
+                single_write,
+                ..Default::default()
+            };
+            for capacity in [0, 10, 50] {
+                let mut writer = FailingWriter {
+                    capacity,
+                    written: 0,
+                };
+                let result = ledger.dump_to_writer_with_options(&mut writer, &options);
+                assert!(
+                    matches!(result, Err(LedgerError::IoError(ref e)) if e.kind() == std::io::ErrorKind::BrokenPipe),
+                    "{result:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_dump_in_a_single_write_when_batching() {
+        let ledger = Ledger::new();
+        for client in [1, 2, 3] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount: dec!(1),
+                })
+                .unwrap();
+        }
+
+        let mut writer = WriteCounter::default();
+        ledger.dump_to_writer(&mut writer).unwrap();
+        assert_eq!(writer.writes, 4);
+
+        let options = DumpOptions {
+            single_write: true,
+            ..Default::default()
+        };
+        let mut batched_writer = WriteCounter::default();
+        ledger
+            .dump_to_writer_with_options(&mut batched_writer, &options)
+            .unwrap();
+        assert_eq!(batched_writer.writes, 1);
+        assert_eq!(batched_writer.output.len(), writer.output.len());
+    }
+
+    /// Blocks on its first write until told to continue
+    struct SlowWriter {
+        output: Vec<u8>,
+        started: std::sync::mpsc::Sender<()>,
+        resume: std::sync::mpsc::Receiver<()>,
+    }
+
+    impl std::io::Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            if self.output.is_empty() {
+                self.started.send(()).unwrap();
+                self.resume.recv().unwrap();
+            }
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_clients_created_during_a_dump_are_excluded() {
+        let deposit = |client| Transaction::Deposit {
+            client,
+            tx: client,
+            amount: dec!(1),
+        };
+        let ledger = Ledger::new();
+        ledger.process_transaction(deposit(1)).unwrap();
+        let (started_tx, started_rx) = std::sync::mpsc::channel();
+        let (resume_tx, resume_rx) = std::sync::mpsc::channel();
+
+        let output = std::thread::scope(|scope| {
+            let dump = scope.spawn(|| {
+                let mut writer = SlowWriter {
+                    output: Vec::new(),
+                    started: started_tx,
+                    resume: resume_rx,
+                };
+                ledger.dump_to_writer(&mut writer).unwrap();
+                writer.output
+            });
+            started_rx.recv().unwrap();
+            // the dump is writing, the store isn't locked anymore
+            ledger.process_transaction(deposit(2)).unwrap();
+            resume_tx.send(()).unwrap();
+            dump.join().unwrap()
+        });
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, available, held, total, locked\n1, 1, 0, 1, false\n"
+        );
+        assert_eq!(ledger.health().clients, 2);
+    }
+
+    #[test]
+    fn test_json_dump_round_trips_to_the_same_balances() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(25.1234),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(3),
+            },
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Chargeback { client: 2, tx: 2 },
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: dec!(7.5),
+            },
+            Transaction::Dispute { client: 3, tx: 3 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        let mut output = Vec::new();
+        ledger.dump_json_to_writer(&mut output).unwrap();
+
+        let rows: Vec<serde_json::Value> = serde_json::from_slice(&output).unwrap();
+        let decimal = |value: &serde_json::Value| value.as_str().unwrap().parse().unwrap();
+        let parsed: HashMap<ClientId, WalletSnapshot> = rows
+            .iter()
+            .map(|row| {
+                let snapshot = WalletSnapshot {
+                    available: decimal(&row["available"]),
+                    held: decimal(&row["held"]),
+                    total: decimal(&row["total"]),
+                    locked: row["locked"].as_bool().unwrap(),
+                    ..Default::default()
+                };
+                (row["client"].as_u64().unwrap() as ClientId, snapshot)
+            })
+            .collect();
+        let expected: HashMap<_, _> = ledger
+            .snapshot()
+            .clients
+            .into_iter()
+            .map(|(client_id, snapshot)| {
+                (
                     client_id,
-                    wallet.get_available(),
-                    wallet.get_held(),
-                    wallet.get_total(),
-                    wallet.get_locked_status()
+                    WalletSnapshot {
+                        available: snapshot.available,
+                        held: snapshot.held,
+                        total: snapshot.total,
+                        locked: snapshot.locked,
+                        ..Default::default()
+                    },
                 )
-                .as_bytes(),
-            )
+            })
+            .collect();
+        assert_eq!(parsed, expected);
+    }
+
+    #[test]
+    fn test_extended_dump_includes_dispute_count() {
+        let ledger = Ledger::new();
+        for transaction in [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+            Transaction::Dispute { client: 1, tx: 1 },
+            Transaction::Resolve { client: 1, tx: 1 },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+
+        let mut output = Vec::new();
+        ledger.dump_to_writer_extended(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, available, held, total, locked, dispute_count\n1, 10, 0, 10, false, 2\n"
+        );
+    }
+
+    #[test]
+    fn test_sorted_dump_orders_clients_inserted_in_reverse() {
+        let ledger = Ledger::new();
+        for client in (1..=5).rev() {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount: Decimal::from(client),
+                })
+                .unwrap();
+        }
+
+        let mut output = Vec::new();
+        ledger.dump_to_writer_sorted(&mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, available, held, total, locked\n\
+             1, 1, 0, 1, false\n\
+             2, 2, 0, 2, false\n\
+             3, 3, 0, 3, false\n\
+             4, 4, 0, 4, false\n\
+             5, 5, 0, 5, false\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_with_fixed_decimal_places() {
+        let ledger = Ledger::new();
+        for (client, amount) in [(1, dec!(25)), (2, dec!(5.1231)), (3, dec!(5.1000))] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount,
+                })
+                .unwrap();
+        }
+        let dump = |decimal_places| {
+            let options = DumpOptions {
+                order: OutputOrder::ByClientId,
+                decimal_places,
+                ..Default::default()
+            };
+            let mut output = Vec::new();
+            ledger
+                .dump_to_writer_with_options(&mut output, &options)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+
+        assert_eq!(
+            dump(None),
+            "client, available, held, total, locked\n\
+             1, 25, 0, 25, false\n\
+             2, 5.1231, 0, 5.1231, false\n\
+             3, 5.1, 0, 5.1, false\n"
+        );
+        assert_eq!(
+            dump(Some(4)),
+            "client, available, held, total, locked\n\
+             1, 25.0000, 0.0000, 25.0000, false\n\
+             2, 5.1231, 0.0000, 5.1231, false\n\
+             3, 5.1000, 0.0000, 5.1000, false\n"
+        );
+    }
+
+    #[test]
+    fn test_dump_trailing_newline_is_optional() {
+        let ledger = Ledger::new();
+        let dump = |options: &DumpOptions| {
+            let mut output = Vec::new();
+            ledger
+                .dump_to_writer_with_options(&mut output, options)
+                .unwrap();
+            output
+        };
+        let mut options = DumpOptions {
+            omit_trailing_newline: true,
+            ..Default::default()
+        };
+        assert_eq!(dump(&options).last(), Some(&b'd'));
+
+        for client in [1, 2] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx: client,
+                    amount: dec!(1),
+                })
+                .unwrap();
+        }
+        assert_eq!(dump(&DumpOptions::default()).last(), Some(&b'\n'));
+        assert_eq!(dump(&options).last(), Some(&b'e'));
+        assert_eq!(dump(&options).iter().filter(|b| **b == b'\n').count(), 2);
+        options.single_write = true;
+        assert_eq!(dump(&options).last(), Some(&b'e'));
+    }
+
+    /// Store keeping its wallets in insertion order, whose `merge` appends the wallets of
+    /// another store without looking for clients in both, like a buggy merge would
+    #[derive(Default, Debug)]
+    struct AppendStore {
+        clients: RwLock<Vec<(ClientId, Mutex<Wallet>)>>,
+    }
+
+    impl AppendStore {
+        fn merge(self, other: AppendStore) -> AppendStore {
+            self.clients.write().extend(other.clients.into_inner());
+            self
+        }
+    }
+
+    impl WalletStore for AppendStore {
+        type Guard<'a> = MappedRwLockReadGuard<'a, Mutex<Wallet>>;
+
+        fn get(&self, client: &ClientId) -> Option<Self::Guard<'_>> {
+            RwLockReadGuard::try_map(self.clients.read(), |clients| {
+                clients.iter().find(|(id, _)| id == client).map(|(_, w)| w)
+            })
+            .ok()
+        }
+
+        fn get_or_create<F>(&self, client: &ClientId, create: F) -> Self::Guard<'_>
+        where
+            F: FnOnce() -> Wallet,
+        {
+            let mut write_lock = self.clients.write();
+            if !write_lock.iter().any(|(id, _)| id == client) {
+                write_lock.push((*client, Mutex::new(create())));
+            }
+            RwLockReadGuard::map(RwLockWriteGuard::downgrade(write_lock), |clients| {
+                clients
+                    .iter()
+                    .find(|(id, _)| id == client)
+                    .map(|(_, w)| w)
+                    .unwrap()
+            })
+        }
+
+        fn for_each<F>(&self, mut f: F)
+        where
+            F: FnMut(ClientId, &Mutex<Wallet>),
+        {
+            for (client_id, wallet) in self.clients.read().iter() {
+                f(*client_id, wallet);
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.clients.read().len()
+        }
+    }
+
+    #[test]
+    fn test_dump_of_merged_ledger_with_collapsed_duplicates_keeps_one_row_per_client() {
+        let deposits = |ledger: &Ledger<AppendStore>, deposits: &[(ClientId, TransactionId)]| {
+            for &(client, tx) in deposits {
+                ledger
+                    .process_transaction(Transaction::Deposit {
+                        client,
+                        tx,
+                        amount: dec!(1),
+                    })
+                    .unwrap();
+            }
+        };
+        let ledger_a = Ledger::with_store(AppendStore::default(), EngineConfig::default());
+        deposits(&ledger_a, &[(1, 1), (2, 2), (1, 3)]);
+        let ledger_b = Ledger::with_store(AppendStore::default(), EngineConfig::default());
+        deposits(&ledger_b, &[(2, 4), (3, 5), (1, 6)]);
+        let merged = Ledger::with_store(
+            ledger_a.clients.merge(ledger_b.clients),
+            EngineConfig::default(),
+        );
+        let dump = |options: &DumpOptions| {
+            let mut output = Vec::new();
+            merged
+                .dump_to_writer_with_options(&mut output, options)
+                .unwrap();
+            String::from_utf8(output).unwrap()
+        };
+        assert_eq!(dump(&DumpOptions::default()).lines().count(), 1 + 5);
+
+        let output = dump(&DumpOptions {
+            collapse_duplicate_clients: true,
+            order: OutputOrder::ByClientId,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            output,
+            "client, available, held, total, locked\n\
+             1, 3, 0, 3, false\n\
+             2, 2, 0, 2, false\n\
+             3, 1, 0, 1, false\n"
+        );
+    }
+
+    #[test]
+    fn test_independently_built_identical_ledgers_are_equal() {
+        let transactions = [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 2,
+                tx: 2,
+                amount: dec!(3),
+            },
+            Transaction::Dispute { client: 1, tx: 1 },
+        ];
+        let ledger_a = Ledger::new();
+        let ledger_b = Ledger::new();
+        for transaction in transactions.iter() {
+            ledger_a.process_transaction(transaction.clone()).unwrap();
+            ledger_b.process_transaction(transaction.clone()).unwrap();
+        }
+        assert_eq!(ledger_a, ledger_b);
+
+        ledger_b
+            .process_transaction(Transaction::Resolve { client: 1, tx: 1 })
             .unwrap();
+        assert_ne!(ledger_a, ledger_b);
+    }
+
+    #[test]
+    fn test_ledger_equality_ignores_config_and_counters() {
+        let ledger_a = Ledger::new();
+        let ledger_b = Ledger::with_config(EngineConfig {
+            max_client_balance: Some(dec!(100)),
+            ..Default::default()
+        });
+        for ledger in [&ledger_a, &ledger_b] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client: 1,
+                    tx: 1,
+                    amount: dec!(10),
+                })
+                .unwrap();
         }
-        w.flush()?;
-        Ok(())
+        // a rejected withdrawal only moves the metrics
+        assert!(ledger_b
+            .process_transaction(Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(11),
+            })
+            .is_err());
+
+        assert_eq!(ledger_a, ledger_b);
+
+        ledger_b
+            .process_transaction(Transaction::Freeze { client: 1, tx: 3 })
+            .unwrap();
+        assert_ne!(ledger_a, ledger_b);
+    }
+
+    #[test]
+    fn test_partial_dispute_amount_is_used_only_when_enabled() {
+        let transactions = [
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::PartialDispute {
+                client: 1,
+                tx: 1,
+                amount: dec!(3),
+            },
+        ];
+        let ledger = Ledger::new();
+        let partial_ledger = Ledger::with_config(EngineConfig {
+            partial_disputes: true,
+            ..Default::default()
+        });
+        for transaction in transactions {
+            ledger.process_transaction(transaction.clone()).unwrap();
+            partial_ledger.process_transaction(transaction).unwrap();
+        }
+
+        assert_eq!(ledger.health().total_held, dec!(10));
+        assert_eq!(partial_ledger.health().total_held, dec!(3));
+    }
+
+    #[test]
+    fn test_with_client_locked_matches_individual_transactions() {
+        let first_deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(1),
+        };
+        let batched = Ledger::new();
+        batched.process_transaction(first_deposit.clone()).unwrap();
+        batched
+            .with_client_locked(1, |wallet| -> Result<(), WalletError> {
+                wallet.deposit(2, dec!(2))?;
+                wallet.deposit(3, dec!(3))?;
+                wallet.withdraw(4, dec!(1))
+            })
+            .unwrap()
+            .unwrap();
+
+        let individual = Ledger::new();
+        for transaction in [
+            first_deposit,
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(2),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 3,
+                amount: dec!(3),
+            },
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 4,
+                amount: dec!(1),
+            },
+        ] {
+            individual.process_transaction(transaction).unwrap();
+        }
+
+        assert_eq!(batched, individual);
+        assert!(matches!(
+            batched.with_client_locked(2, |_| ()),
+            Err(LedgerError::InexistentClient(2))
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_delta_reports_created_and_locked_clients() {
+        let ledger = Ledger::new();
+        for (client, tx) in [(1, 1), (2, 2)] {
+            ledger
+                .process_transaction(Transaction::Deposit {
+                    client,
+                    tx,
+                    amount: dec!(10),
+                })
+                .unwrap();
+        }
+        let before = ledger.snapshot();
+        for transaction in [
+            Transaction::Dispute { client: 2, tx: 2 },
+            Transaction::Chargeback { client: 2, tx: 2 },
+            Transaction::Deposit {
+                client: 3,
+                tx: 3,
+                amount: dec!(1.5),
+            },
+        ] {
+            ledger.process_transaction(transaction).unwrap();
+        }
+        let after = ledger.snapshot();
+
+        let mut output = Vec::new();
+        before.dump_delta_to_writer(&after, &mut output).unwrap();
+
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client, change, available, held, was_locked, locked
+2, updated, -10, 0, false, true
+3, created, 1.5, 0, false, false
+"
+        );
     }
 }
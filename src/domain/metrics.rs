@@ -0,0 +1,226 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{LedgerError, TransactionType, WalletError};
+
+/// Why the `Ledger` rejected a transaction, as counted by `LedgerMetrics`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RejectionKind {
+    InexistentClient,
+    DepositIdExists,
+    InexistentTransaction,
+    InsufficientFunds,
+    BelowMinimumBalance,
+    DepositBelowMinimum,
+    InvalidAmount,
+    BalanceCeilingExceeded,
+    AccountLocked,
+    ZeroAmountDispute,
+    DisputeChurnLimit,
+    Overflow,
+    AlreadyDisputed,
+    InvariantViolation,
+    /// The referenced deposit isn't in a state allowing the transaction
+    InvalidDepositState,
+    /// The referenced transaction belongs to another client
+    ClientMismatch,
+    DuplicateTransactionId,
+    Other,
+}
+
+impl RejectionKind {
+    const ALL: [RejectionKind; 18] = [
+        RejectionKind::InexistentClient,
+        RejectionKind::DepositIdExists,
+        RejectionKind::InexistentTransaction,
+        RejectionKind::InsufficientFunds,
+        RejectionKind::BelowMinimumBalance,
+        RejectionKind::DepositBelowMinimum,
+        RejectionKind::InvalidAmount,
+        RejectionKind::BalanceCeilingExceeded,
+        RejectionKind::AccountLocked,
+        RejectionKind::ZeroAmountDispute,
+        RejectionKind::DisputeChurnLimit,
+        RejectionKind::Overflow,
+        RejectionKind::AlreadyDisputed,
+        RejectionKind::InvariantViolation,
+        RejectionKind::InvalidDepositState,
+        RejectionKind::ClientMismatch,
+        RejectionKind::DuplicateTransactionId,
+        RejectionKind::Other,
+    ];
+
+    pub fn of(error: &LedgerError) -> Self {
+        match error {
+            LedgerError::InexistentClient(_) => RejectionKind::InexistentClient,
+            LedgerError::ClientMismatch { .. } => RejectionKind::ClientMismatch,
+            LedgerError::DuplicateTransactionId(_) => RejectionKind::DuplicateTransactionId,
+            LedgerError::WalletError(e) => Self::of_wallet_error(e),
+            _ => RejectionKind::Other,
+        }
+    }
+
+    pub fn of_wallet_error(error: &WalletError) -> Self {
+        match error {
+            WalletError::DepositIdExists => RejectionKind::DepositIdExists,
+            WalletError::InexistentTransaction => RejectionKind::InexistentTransaction,
+            WalletError::InsufficientFunds => RejectionKind::InsufficientFunds,
+            WalletError::BelowMinimumBalance => RejectionKind::BelowMinimumBalance,
+            WalletError::DepositBelowMinimum(_) => RejectionKind::DepositBelowMinimum,
+            WalletError::InvalidAmount(_) => RejectionKind::InvalidAmount,
+            WalletError::BalanceCeilingExceeded => RejectionKind::BalanceCeilingExceeded,
+            WalletError::AccountLocked => RejectionKind::AccountLocked,
+            WalletError::ZeroAmountDispute => RejectionKind::ZeroAmountDispute,
+            WalletError::DisputeChurnLimit => RejectionKind::DisputeChurnLimit,
+            WalletError::Overflow => RejectionKind::Overflow,
+            WalletError::AlreadyDisputed => RejectionKind::AlreadyDisputed,
+            WalletError::InvariantViolation => RejectionKind::InvariantViolation,
+            WalletError::DepositLogError(_) => RejectionKind::InvalidDepositState,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            RejectionKind::InexistentClient => "inexistent_client",
+            RejectionKind::DepositIdExists => "deposit_id_exists",
+            RejectionKind::InexistentTransaction => "inexistent_transaction",
+            RejectionKind::InsufficientFunds => "insufficient_funds",
+            RejectionKind::BelowMinimumBalance => "below_minimum_balance",
+            RejectionKind::DepositBelowMinimum => "deposit_below_minimum",
+            RejectionKind::InvalidAmount => "invalid_amount",
+            RejectionKind::BalanceCeilingExceeded => "balance_ceiling_exceeded",
+            RejectionKind::AccountLocked => "account_locked",
+            RejectionKind::ZeroAmountDispute => "zero_amount_dispute",
+            RejectionKind::DisputeChurnLimit => "dispute_churn_limit",
+            RejectionKind::Overflow => "overflow",
+            RejectionKind::AlreadyDisputed => "already_disputed",
+            RejectionKind::InvariantViolation => "invariant_violation",
+            RejectionKind::InvalidDepositState => "invalid_deposit_state",
+            RejectionKind::ClientMismatch => "client_mismatch",
+            RejectionKind::DuplicateTransactionId => "duplicate_transaction_id",
+            RejectionKind::Other => "other",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// Counters updated by the `Ledger` for every processed transaction.
+/// Atomics, so they can be updated through `&Ledger` from any thread.
+#[derive(Default, Debug)]
+pub struct LedgerMetrics {
+    transactions: [AtomicU64; TransactionType::ALL.len()],
+    errors: AtomicU64,
+    rejections: [AtomicU64; RejectionKind::ALL.len()],
+    skipped: AtomicU64,
+}
+
+impl LedgerMetrics {
+    pub(crate) fn record(
+        &self,
+        transaction_type: TransactionType,
+        result: &Result<(), LedgerError>,
+    ) {
+        self.transactions[Self::index(transaction_type)].fetch_add(1, Ordering::Relaxed);
+        if let Err(e) = result {
+            self.errors.fetch_add(1, Ordering::Relaxed);
+            self.rejections[RejectionKind::of(e).index()].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_skipped(&self) {
+        self.skipped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Transactions of `transaction_type` processed so far, including the rejected ones
+    pub fn transactions(&self, transaction_type: TransactionType) -> u64 {
+        self.transactions[Self::index(transaction_type)].load(Ordering::Relaxed)
+    }
+
+    /// Transactions rejected by the ledger
+    pub fn errors(&self) -> u64 {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// Transactions rejected by the ledger for `kind` of reason
+    pub fn rejections(&self, kind: RejectionKind) -> u64 {
+        self.rejections[kind.index()].load(Ordering::Relaxed)
+    }
+
+    /// Transactions left out by the `EngineConfig::type_filter`, not counted as processed
+    pub fn skipped(&self) -> u64 {
+        self.skipped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn write_prometheus<W>(&self, w: &mut W) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        writeln!(
+            w,
+            "# HELP payments_transactions_total Transactions processed, by type"
+        )?;
+        writeln!(w, "# TYPE payments_transactions_total counter")?;
+        for transaction_type in TransactionType::ALL {
+            writeln!(
+                w,
+                "payments_transactions_total{{type=\"{}\"}} {}",
+                transaction_type.as_str(),
+                self.transactions(transaction_type)
+            )?;
+        }
+        writeln!(
+            w,
+            "# HELP payments_transaction_errors_total Transactions rejected by the ledger"
+        )?;
+        writeln!(w, "# TYPE payments_transaction_errors_total counter")?;
+        writeln!(w, "payments_transaction_errors_total {}", self.errors())?;
+        writeln!(
+            w,
+            "# HELP payments_transaction_rejections_total Transactions rejected by the ledger, by reason"
+        )?;
+        writeln!(w, "# TYPE payments_transaction_rejections_total counter")?;
+        for kind in RejectionKind::ALL {
+            writeln!(
+                w,
+                "payments_transaction_rejections_total{{reason=\"{}\"}} {}",
+                kind.label(),
+                self.rejections(kind)
+            )?;
+        }
+        writeln!(
+            w,
+            "# HELP payments_transactions_skipped_total Transactions left out by the type filter"
+        )?;
+        writeln!(w, "# TYPE payments_transactions_skipped_total counter")?;
+        writeln!(w, "payments_transactions_skipped_total {}", self.skipped())
+    }
+
+    fn index(transaction_type: TransactionType) -> usize {
+        match transaction_type {
+            TransactionType::Deposit => 0,
+            TransactionType::Withdrawal => 1,
+            TransactionType::Dispute => 2,
+            TransactionType::Resolve => 3,
+            TransactionType::Chargeback => 4,
+            TransactionType::Freeze => 5,
+            TransactionType::Unfreeze => 6,
+        }
+    }
+}
+
+impl Clone for LedgerMetrics {
+    fn clone(&self) -> Self {
+        Self {
+            transactions: std::array::from_fn(|i| {
+                AtomicU64::new(self.transactions[i].load(Ordering::Relaxed))
+            }),
+            errors: AtomicU64::new(self.errors()),
+            rejections: std::array::from_fn(|i| {
+                AtomicU64::new(self.rejections[i].load(Ordering::Relaxed))
+            }),
+            skipped: AtomicU64::new(self.skipped()),
+        }
+    }
+}
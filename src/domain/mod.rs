@@ -1,13 +1,39 @@
+mod config;
 mod deposit_log;
+mod dump;
 mod ledger;
+mod metrics;
+mod snapshot;
+mod store;
 mod transaction;
 mod wallet;
 
+pub use config::{EngineConfig, LockPolicy, ZeroAmountDisputes};
+pub use deposit_log::{DepositLog, DepositLogError, DepositStatus};
+pub use dump::*;
 pub use ledger::*;
-pub use transaction::Transaction;
+pub use metrics::{LedgerMetrics, RejectionKind};
+pub use snapshot::*;
+pub use store::*;
+pub use transaction::{ParseTransactionTypeError, Transaction, TransactionType};
 pub use wallet::*;
 
 pub use rust_decimal::Decimal;
 
-pub type ClientId = u16;
+/// Client id as it appears in a single input
+pub type InputClientId = u16;
+/// Client id within the `Ledger`, wide enough to namespace the `InputClientId`s of
+/// several tenants
+pub type ClientId = u32;
 pub type TransactionId = u32;
+
+/// Combines a tenant's `namespace` with a client id from its input, so equal ids from
+/// different tenants stay distinct. Namespace 0 keeps the input id unchanged.
+pub fn namespaced_client_id(namespace: u16, client: InputClientId) -> ClientId {
+    (ClientId::from(namespace) << InputClientId::BITS) | ClientId::from(client)
+}
+
+/// The namespace `client` was created in by `namespaced_client_id`
+pub fn client_namespace(client: ClientId) -> u16 {
+    (client >> InputClientId::BITS) as u16
+}
@@ -0,0 +1,109 @@
+use std::collections::BTreeMap;
+
+use super::{ClientId, Decimal, WalletSnapshot};
+
+/// Balances of every client captured by `Ledger::snapshot()`, ordered by `ClientId`
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct LedgerSnapshot {
+    pub clients: BTreeMap<ClientId, WalletSnapshot>,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ClientChange {
+    Created,
+    Removed,
+    Updated,
+}
+
+impl ClientChange {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ClientChange::Created => "created",
+            ClientChange::Removed => "removed",
+            ClientChange::Updated => "updated",
+        }
+    }
+}
+
+/// Change of a client between two `LedgerSnapshot`s. Balances are `after - before`,
+/// a missing client counts as zero balances and unlocked.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ClientDelta {
+    pub client: ClientId,
+    pub change: ClientChange,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub was_locked: bool,
+    pub locked: bool,
+}
+
+impl LedgerSnapshot {
+    /// Per-client changes from `self` to `after`, ordered by `ClientId`.
+    /// Clients whose `available`, `held` and `locked` didn't change are omitted, even if
+    /// their counters did, e.g. after a dispute and its resolve.
+    pub fn delta(&self, after: &LedgerSnapshot) -> Vec<ClientDelta> {
+        let empty = WalletSnapshot::default();
+        let mut client_ids: Vec<_> = self.clients.keys().chain(after.clients.keys()).collect();
+        client_ids.sort();
+        client_ids.dedup();
+
+        client_ids
+            .into_iter()
+            .filter_map(|client| {
+                let before_wallet = self.clients.get(client);
+                let after_wallet = after.clients.get(client);
+                let change = match (before_wallet, after_wallet) {
+                    (None, _) => ClientChange::Created,
+                    (_, None) => ClientChange::Removed,
+                    (Some(before_wallet), Some(after_wallet))
+                        if (
+                            before_wallet.available,
+                            before_wallet.held,
+                            before_wallet.locked,
+                        ) == (
+                            after_wallet.available,
+                            after_wallet.held,
+                            after_wallet.locked,
+                        ) =>
+                    {
+                        return None
+                    }
+                    _ => ClientChange::Updated,
+                };
+                let before_wallet = before_wallet.unwrap_or(&empty);
+                let after_wallet = after_wallet.unwrap_or(&empty);
+                Some(ClientDelta {
+                    client: *client,
+                    change,
+                    available: after_wallet.available - before_wallet.available,
+                    held: after_wallet.held - before_wallet.held,
+                    was_locked: before_wallet.locked,
+                    locked: after_wallet.locked,
+                })
+            })
+            .collect()
+    }
+
+    /// Writes the `delta()` to `after` as CSV, suitable for reconciliation
+    pub fn dump_delta_to_writer<W>(&self, after: &LedgerSnapshot, w: &mut W) -> std::io::Result<()>
+    where
+        W: std::io::Write,
+    {
+        w.write_all("client, change, available, held, was_locked, locked\n".as_bytes())?;
+        for delta in self.delta(after) {
+            w.write_all(
+                format!(
+                    "{}, {}, {}, {}, {}, {}\n",
+                    delta.client,
+                    delta.change.as_str(),
+                    delta.available,
+                    delta.held,
+                    delta.was_locked,
+                    delta.locked
+                )
+                .as_bytes(),
+            )?;
+        }
+        w.flush()
+    }
+}
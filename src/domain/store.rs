@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use parking_lot::{MappedRwLockReadGuard, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use super::{ClientId, Wallet};
+
+/// Storage of the clients' wallets used by the `Ledger`.
+///
+/// Every wallet sits behind its own `Mutex`: the store only hands out guards to those
+/// `Mutex`es, so other threads can keep using the store while a wallet is locked.
+pub trait WalletStore: Default + Send + Sync {
+    type Guard<'a>: Deref<Target = Mutex<Wallet>>
+    where
+        Self: 'a;
+
+    fn get(&self, client: &ClientId) -> Option<Self::Guard<'_>>;
+
+    /// `create` is only called if the client doesn't exist yet
+    fn get_or_create<F>(&self, client: &ClientId, create: F) -> Self::Guard<'_>
+    where
+        F: FnOnce() -> Wallet;
+
+    /// Calls `f` for every client, in no particular order
+    fn for_each<F>(&self, f: F)
+    where
+        F: FnMut(ClientId, &Mutex<Wallet>);
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The default `WalletStore`, keeping the wallets in a `HashMap` behind a `RwLock`
+#[derive(Default, Debug)]
+pub struct InMemoryStore {
+    clients: RwLock<HashMap<ClientId, Mutex<Wallet>>>,
+}
+
+impl WalletStore for InMemoryStore {
+    /// A MappedRwLockReadGuard because the `Mutex<Wallet>` references the read-lock.
+    type Guard<'a> = MappedRwLockReadGuard<'a, Mutex<Wallet>>;
+
+    fn get(&self, client: &ClientId) -> Option<Self::Guard<'_>> {
+        let read_lock = self.clients.read();
+
+        RwLockReadGuard::try_map(read_lock, |hm| hm.get(client)).ok()
+    }
+
+    /// We first try to find the client through a read-lock so other threads can also read
+    /// the store. If it doesn't exist, we need a write-lock to create the Client
+    fn get_or_create<F>(&self, client: &ClientId, create: F) -> Self::Guard<'_>
+    where
+        F: FnOnce() -> Wallet,
+    {
+        let read_lock = self.clients.read();
+        if read_lock.contains_key(client) {
+            RwLockReadGuard::map(read_lock, |hm| hm.get(client).unwrap())
+        } else {
+            // Drop read lock to avoid deadlock
+            drop(read_lock);
+            // We need a write-lock to add a new client
+            let mut write_lock = self.clients.write();
+            // Use entry instead of insert, in case another thread created
+            // the client in the time between the dropping of the read-lock
+            // and aquiring the write-lock
+            let _ = write_lock
+                .entry(*client)
+                .or_insert_with(|| Mutex::new(create()));
+
+            // Downgrade the write-lock to a read-lock and return
+            RwLockReadGuard::map(
+                RwLockWriteGuard::downgrade(write_lock),
+                |hm: &HashMap<ClientId, Mutex<Wallet>>| hm.get(client).unwrap(),
+            )
+        }
+    }
+
+    fn for_each<F>(&self, mut f: F)
+    where
+        F: FnMut(ClientId, &Mutex<Wallet>),
+    {
+        for (client_id, wallet) in self.clients.read().iter() {
+            f(*client_id, wallet);
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.clients.read().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::domain::{EngineConfig, Ledger, Transaction};
+    use rust_decimal_macros::dec;
+
+    /// Trivial store always creating clients under the write-lock, ordered by `ClientId`
+    #[derive(Default, Debug)]
+    struct BTreeStore {
+        clients: RwLock<BTreeMap<ClientId, Mutex<Wallet>>>,
+    }
+
+    impl WalletStore for BTreeStore {
+        type Guard<'a> = MappedRwLockReadGuard<'a, Mutex<Wallet>>;
+
+        fn get(&self, client: &ClientId) -> Option<Self::Guard<'_>> {
+            RwLockReadGuard::try_map(self.clients.read(), |bm| bm.get(client)).ok()
+        }
+
+        fn get_or_create<F>(&self, client: &ClientId, create: F) -> Self::Guard<'_>
+        where
+            F: FnOnce() -> Wallet,
+        {
+            let mut write_lock = self.clients.write();
+            write_lock
+                .entry(*client)
+                .or_insert_with(|| Mutex::new(create()));
+            RwLockReadGuard::map(RwLockWriteGuard::downgrade(write_lock), |bm| {
+                bm.get(client).unwrap()
+            })
+        }
+
+        fn for_each<F>(&self, mut f: F)
+        where
+            F: FnMut(ClientId, &Mutex<Wallet>),
+        {
+            for (client_id, wallet) in self.clients.read().iter() {
+                f(*client_id, wallet);
+            }
+        }
+
+        fn len(&self) -> usize {
+            self.clients.read().len()
+        }
+    }
+
+    #[test]
+    fn test_ledger_processes_transactions_through_alternate_store() {
+        let transactions = [
+            Transaction::Deposit {
+                client: 2,
+                tx: 1,
+                amount: dec!(10),
+            },
+            Transaction::Deposit {
+                client: 1,
+                tx: 2,
+                amount: dec!(4),
+            },
+            Transaction::Withdrawal {
+                client: 2,
+                tx: 3,
+                amount: dec!(3),
+            },
+            Transaction::Dispute { client: 1, tx: 2 },
+            Transaction::Withdrawal {
+                client: 3,
+                tx: 4,
+                amount: dec!(1),
+            },
+        ];
+        let ledger = Ledger::new();
+        let btree_ledger = Ledger::with_store(BTreeStore::default(), EngineConfig::default());
+        for transaction in transactions {
+            let result = ledger.process_transaction(transaction.clone());
+            let btree_result = btree_ledger.process_transaction(transaction);
+            assert_eq!(result.is_ok(), btree_result.is_ok());
+        }
+
+        assert_eq!(btree_ledger.health(), ledger.health());
+        assert_eq!(btree_ledger.snapshot(), ledger.snapshot());
+    }
+}
@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use crate::domain::Decimal;
 use crate::domain::{ClientId, TransactionId};
 
@@ -17,6 +19,12 @@ pub enum Transaction {
         client: ClientId,
         tx: TransactionId,
     },
+    /// Dispute row carrying an explicit amount
+    PartialDispute {
+        client: ClientId,
+        tx: TransactionId,
+        amount: Decimal,
+    },
     Resolve {
         client: ClientId,
         tx: TransactionId,
@@ -25,16 +33,108 @@ pub enum Transaction {
         client: ClientId,
         tx: TransactionId,
     },
+    /// Operator locking the account without touching its balances. The `tx` only
+    /// identifies the row in logs and reports, it doesn't reference a deposit.
+    Freeze {
+        client: ClientId,
+        tx: TransactionId,
+    },
+    /// Operator unlocking the account, whatever locked it
+    Unfreeze {
+        client: ClientId,
+        tx: TransactionId,
+    },
+}
+
+/// Kind of a `Transaction`, as named in the `type` column of the input
+#[derive(serde::Deserialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    #[serde(alias = "withdraw")]
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Freeze,
+    Unfreeze,
+}
+
+#[derive(thiserror::Error, Debug, PartialEq, Clone)]
+#[error("Unknown transaction type `{0}`")]
+pub struct ParseTransactionTypeError(pub String);
+
+impl TransactionType {
+    pub const ALL: [TransactionType; 7] = [
+        TransactionType::Deposit,
+        TransactionType::Withdrawal,
+        TransactionType::Dispute,
+        TransactionType::Resolve,
+        TransactionType::Chargeback,
+        TransactionType::Freeze,
+        TransactionType::Unfreeze,
+    ];
+
+    /// The name used in the `type` column, the same serde deserializes
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Unfreeze => "unfreeze",
+        }
+    }
+}
+
+/// Accepts the same names as serde, including the `withdraw` alias
+impl FromStr for TransactionType {
+    type Err = ParseTransactionTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "withdraw" => Ok(TransactionType::Withdrawal),
+            _ => TransactionType::ALL
+                .into_iter()
+                .find(|transaction_type| transaction_type.as_str() == s)
+                .ok_or_else(|| ParseTransactionTypeError(s.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 impl Transaction {
+    /// A `PartialDispute` is a `Dispute`
+    pub fn get_type(&self) -> TransactionType {
+        match self {
+            Transaction::Deposit { .. } => TransactionType::Deposit,
+            Transaction::Withdrawal { .. } => TransactionType::Withdrawal,
+            Transaction::Dispute { .. } | Transaction::PartialDispute { .. } => {
+                TransactionType::Dispute
+            }
+            Transaction::Resolve { .. } => TransactionType::Resolve,
+            Transaction::Chargeback { .. } => TransactionType::Chargeback,
+            Transaction::Freeze { .. } => TransactionType::Freeze,
+            Transaction::Unfreeze { .. } => TransactionType::Unfreeze,
+        }
+    }
     pub fn get_transaction_id(&self) -> TransactionId {
         match self {
             Transaction::Deposit { tx, .. }
             | Transaction::Withdrawal { tx, .. }
             | Transaction::Dispute { tx, .. }
+            | Transaction::PartialDispute { tx, .. }
             | Transaction::Resolve { tx, .. }
-            | Transaction::Chargeback { tx, .. } => *tx,
+            | Transaction::Chargeback { tx, .. }
+            | Transaction::Freeze { tx, .. }
+            | Transaction::Unfreeze { tx, .. } => *tx,
         }
     }
     pub fn get_client_id(&self) -> ClientId {
@@ -42,8 +142,159 @@ impl Transaction {
             Transaction::Deposit { client, .. }
             | Transaction::Withdrawal { client, .. }
             | Transaction::Dispute { client, .. }
+            | Transaction::PartialDispute { client, .. }
             | Transaction::Resolve { client, .. }
-            | Transaction::Chargeback { client, .. } => *client,
+            | Transaction::Chargeback { client, .. }
+            | Transaction::Freeze { client, .. }
+            | Transaction::Unfreeze { client, .. } => *client,
+        }
+    }
+    /// Amount moved by a deposit or withdrawal. `None` for the dispute family, including a
+    /// `PartialDispute` whose amount only selects part of an existing deposit, and for
+    /// freezes and unfreezes.
+    pub fn amount(&self) -> Option<Decimal> {
+        match self {
+            Transaction::Deposit { amount, .. } | Transaction::Withdrawal { amount, .. } => {
+                Some(*amount)
+            }
+            Transaction::Dispute { .. }
+            | Transaction::PartialDispute { .. }
+            | Transaction::Resolve { .. }
+            | Transaction::Chargeback { .. }
+            | Transaction::Freeze { .. }
+            | Transaction::Unfreeze { .. } => None,
+        }
+    }
+}
+
+impl std::fmt::Display for Transaction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transaction::Deposit { client, tx, amount } => {
+                write!(f, "deposit client={client} tx={tx} amount={amount}")
+            }
+            Transaction::Withdrawal { client, tx, amount } => {
+                write!(f, "withdrawal client={client} tx={tx} amount={amount}")
+            }
+            Transaction::Dispute { client, tx } => write!(f, "dispute client={client} tx={tx}"),
+            Transaction::PartialDispute { client, tx, amount } => {
+                write!(f, "dispute client={client} tx={tx} amount={amount}")
+            }
+            Transaction::Resolve { client, tx } => write!(f, "resolve client={client} tx={tx}"),
+            Transaction::Chargeback { client, tx } => {
+                write!(f, "chargeback client={client} tx={tx}")
+            }
+            Transaction::Freeze { client, tx } => write!(f, "freeze client={client} tx={tx}"),
+            Transaction::Unfreeze { client, tx } => {
+                write!(f, "unfreeze client={client} tx={tx}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn test_amount_is_only_returned_for_deposits_and_withdrawals() {
+        assert_eq!(
+            Transaction::Deposit {
+                client: 1,
+                tx: 1,
+                amount: dec!(5),
+            }
+            .amount(),
+            Some(dec!(5))
+        );
+        assert_eq!(
+            Transaction::Withdrawal {
+                client: 1,
+                tx: 2,
+                amount: dec!(1.5),
+            }
+            .amount(),
+            Some(dec!(1.5))
+        );
+        assert_eq!(Transaction::Dispute { client: 1, tx: 1 }.amount(), None);
+        assert_eq!(
+            Transaction::PartialDispute {
+                client: 1,
+                tx: 1,
+                amount: dec!(2),
+            }
+            .amount(),
+            None
+        );
+        assert_eq!(Transaction::Resolve { client: 1, tx: 1 }.amount(), None);
+        assert_eq!(Transaction::Chargeback { client: 1, tx: 1 }.amount(), None);
+        assert_eq!(Transaction::Freeze { client: 1, tx: 3 }.amount(), None);
+        assert_eq!(Transaction::Unfreeze { client: 1, tx: 4 }.amount(), None);
+    }
+
+    #[test]
+    fn test_transaction_type_round_trips_through_its_name() {
+        for transaction_type in TransactionType::ALL {
+            let name = transaction_type.as_str();
+            assert_eq!(name.parse(), Ok(transaction_type));
+            assert_eq!(transaction_type.to_string(), name);
+            let deserialized: TransactionType =
+                serde_json::from_value(serde_json::Value::from(name)).unwrap();
+            assert_eq!(deserialized, transaction_type);
         }
+        assert_eq!("withdraw".parse(), Ok(TransactionType::Withdrawal));
+        assert_eq!(
+            "Deposit".parse::<TransactionType>(),
+            Err(ParseTransactionTypeError("Deposit".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_display_is_compact_for_all_variants() {
+        let deposit = Transaction::Deposit {
+            client: 1,
+            tx: 1,
+            amount: dec!(5),
+        };
+        let withdrawal = Transaction::Withdrawal {
+            client: 1,
+            tx: 2,
+            amount: dec!(1.5),
+        };
+        assert_eq!(deposit.to_string(), "deposit client=1 tx=1 amount=5");
+        assert_eq!(
+            withdrawal.to_string(),
+            "withdrawal client=1 tx=2 amount=1.5"
+        );
+        assert_eq!(
+            Transaction::Dispute { client: 1, tx: 1 }.to_string(),
+            "dispute client=1 tx=1"
+        );
+        assert_eq!(
+            Transaction::PartialDispute {
+                client: 1,
+                tx: 1,
+                amount: dec!(2)
+            }
+            .to_string(),
+            "dispute client=1 tx=1 amount=2"
+        );
+        assert_eq!(
+            Transaction::Resolve { client: 1, tx: 1 }.to_string(),
+            "resolve client=1 tx=1"
+        );
+        assert_eq!(
+            Transaction::Chargeback { client: 1, tx: 1 }.to_string(),
+            "chargeback client=1 tx=1"
+        );
+        assert_eq!(
+            Transaction::Freeze { client: 1, tx: 3 }.to_string(),
+            "freeze client=1 tx=3"
+        );
+        assert_eq!(
+            Transaction::Unfreeze { client: 1, tx: 4 }.to_string(),
+            "unfreeze client=1 tx=4"
+        );
     }
 }
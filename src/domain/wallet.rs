@@ -1,35 +1,135 @@
 use std::collections::{hash_map, HashMap};
+use std::sync::Arc;
 
-use crate::domain::{Decimal, TransactionId};
+use borsh::{BorshDeserialize, BorshSerialize};
 
-use super::deposit_log::{DepositLog, DepositLogError};
+use crate::domain::{
+    ClientId, Decimal, EngineConfig, LockPolicy, OutputColumns, TransactionId, ZeroAmountDisputes,
+};
 
-#[derive(thiserror::Error, Debug, PartialEq)]
+use super::deposit_log::{DepositLog, DepositLogError, DepositStatus};
+
+#[derive(thiserror::Error, Debug, PartialEq, Clone)]
 pub enum WalletError {
     #[error("DepositId exists")]
     DepositIdExists,
-    #[error("Disputed transaction doesn't exist")]
+    #[error("Referenced deposit doesn't exist for this client")]
     InexistentTransaction,
     #[error("Insufficient funds")]
     InsufficientFunds,
+    #[error("Withdrawal would leave less than the minimum balance")]
+    BelowMinimumBalance,
+    #[error("Deposit of {0} is below the minimum")]
+    DepositBelowMinimum(Decimal),
+    #[error("Invalid amount: {0}")]
+    InvalidAmount(Decimal),
+    #[error("Deposit would exceed the client balance ceiling")]
+    BalanceCeilingExceeded,
+    #[error("Account is locked")]
+    AccountLocked,
+    #[error("Can't dispute a zero-amount deposit")]
+    ZeroAmountDispute,
+    #[error("Dispute cycle limit reached for transaction")]
+    DisputeChurnLimit,
+    #[error("Balance can't be represented")]
+    Overflow,
+    #[error("Deposit is already disputed")]
+    AlreadyDisputed,
+    #[error("Held funds would become negative")]
+    InvariantViolation,
     #[error("DepositLog error: {0}")]
-    DepositLogError(#[from] DepositLogError),
+    DepositLogError(DepositLogError),
+}
+
+/// `AlreadyDisputed` gets its own variant, so callers can tell it apart from the
+/// other invalid transitions without matching on `DepositLogError`
+impl From<DepositLogError> for WalletError {
+    fn from(e: DepositLogError) -> Self {
+        match e {
+            DepositLogError::AlreadyDisputed => WalletError::AlreadyDisputed,
+            e => WalletError::DepositLogError(e),
+        }
+    }
+}
+
+/// Why a `Wallet` got locked
+#[derive(Debug, PartialEq, Clone, Copy, BorshSerialize, BorshDeserialize)]
+pub enum LockReason {
+    Chargeback(TransactionId),
+    /// Frozen by an operator through a `Transaction::Freeze`
+    Frozen,
+}
+
+impl std::fmt::Display for LockReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LockReason::Chargeback(tx) => write!(f, "chargeback of tx {tx}"),
+            LockReason::Frozen => f.write_str("manual freeze"),
+        }
+    }
+}
+
+/// Balances of a `Wallet` captured at one point in time
+#[derive(Default, Debug, PartialEq, Clone, Copy)]
+pub struct WalletSnapshot {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    pub tx_count: u64,
+    pub dispute_count: u64,
+}
+
+impl WalletSnapshot {
+    /// The row of `client_id` in the default dump, matching `Ledger::CSV_HEADER`
+    pub fn to_csv_row(&self, client_id: ClientId) -> String {
+        OutputColumns::default().row(client_id, self)
+    }
 }
 
-#[derive(Default, Debug, PartialEq)]
+/// Serialized by `Ledger::save_snapshot`, without the config which belongs to the `Ledger`
+#[derive(Default, Debug, PartialEq, Clone, BorshSerialize, BorshDeserialize)]
 pub struct Wallet {
     available: Decimal,
     held: Decimal,
-    locked: bool,
+    lock_reason: Option<LockReason>,
     deposit_log: HashMap<TransactionId, DepositLog>,
+    pending_deposits: Vec<(TransactionId, Decimal)>,
+    tx_count: u64,
+    dispute_count: u64,
+    #[borsh(skip)]
+    config: Arc<EngineConfig>,
 }
 
 impl Wallet {
+    pub fn new(config: Arc<EngineConfig>) -> Self {
+        Self {
+            config,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn set_config(&mut self, config: Arc<EngineConfig>) {
+        self.config = config;
+    }
+
     pub fn deposit(&mut self, tx: TransactionId, amount: Decimal) -> Result<(), WalletError> {
+        ensure_valid_deposit(&self.config, amount)?;
+        if self.lock_reason.is_some() && self.config.lock_policy == LockPolicy::RejectAll {
+            return self.queue_pending_deposit(tx, amount);
+        }
         // if 'tx' exists in transaction_log don't increase balances
         if let hash_map::Entry::Vacant(transaction_map) = self.deposit_log.entry(tx) {
+            let available = checked_add(self.available, amount)?;
+            if let Some(max_balance) = self.config.max_client_balance {
+                if checked_add(available, self.held)? > max_balance {
+                    return Err(WalletError::BalanceCeilingExceeded);
+                }
+            }
             transaction_map.insert(DepositLog::new(amount));
-            self.available += amount;
+            self.available = available;
+            self.tx_count += 1;
+            self.normalize_balances();
             Ok(())
         } else {
             Err(WalletError::DepositIdExists)
@@ -37,20 +137,85 @@ impl Wallet {
     }
 
     pub fn withdraw(&mut self, _tx: TransactionId, amount: Decimal) -> Result<(), WalletError> {
-        if self.available >= amount {
-            self.available -= amount;
-            Ok(())
-        } else {
-            Err(WalletError::InsufficientFunds)
+        if amount <= Decimal::ZERO {
+            return Err(WalletError::InvalidAmount(amount));
         }
+        self.ensure_unlocked()?;
+        if self.available < amount {
+            return Err(WalletError::InsufficientFunds);
+        }
+        let available = checked_sub(self.available, amount)?;
+        if let Some(minimum_balance) = self.config.require_minimum_balance {
+            if available < minimum_balance {
+                return Err(WalletError::BelowMinimumBalance);
+            }
+        }
+        self.available = available;
+        self.tx_count += 1;
+        self.normalize_balances();
+        Ok(())
     }
 
     pub fn dispute(&mut self, tx: TransactionId) -> Result<(), WalletError> {
+        self.dispute_amount(tx, None)
+    }
+
+    /// Disputes only `amount` out of the deposit, which must not exceed the deposited amount.
+    /// While partially disputed, further disputes of the deposit add to the held amount, up
+    /// to the whole deposit; a dispute without amount holds all of the remaining part.
+    /// Resolving or charging back the dispute moves everything held for the deposit, the
+    /// undisputed part stays available.
+    pub fn dispute_partial(
+        &mut self,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        self.dispute_amount(tx, Some(amount))
+    }
+
+    fn dispute_amount(
+        &mut self,
+        tx: TransactionId,
+        amount: Option<Decimal>,
+    ) -> Result<(), WalletError> {
+        self.ensure_unlocked()?;
         if let Some(logged_transaction) = self.deposit_log.get_mut(&tx) {
-            logged_transaction.set_disputed()?;
-            let disputed_amount = logged_transaction.get_amount();
-            self.available -= disputed_amount;
-            self.held += disputed_amount;
+            if logged_transaction.get_amount().is_zero() {
+                match self.config.zero_amount_disputes {
+                    ZeroAmountDisputes::Allow => {}
+                    ZeroAmountDisputes::Reject => return Err(WalletError::ZeroAmountDispute),
+                    ZeroAmountDisputes::Ignore => return Ok(()),
+                }
+            }
+            // update a copy, so the deposit is left untouched if the balances overflow
+            let mut disputed = logged_transaction.clone();
+            let disputed_amount = if logged_transaction.is_disputed() {
+                disputed.extend_dispute(amount)?
+            } else {
+                if let Some(amount) = amount {
+                    logged_transaction.validate_disputed_amount(amount)?;
+                }
+                if let Some(max_cycles) = self.config.max_dispute_cycles {
+                    if logged_transaction.get_dispute_cycles() >= max_cycles {
+                        return Err(WalletError::DisputeChurnLimit);
+                    }
+                }
+                if self.config.forbid_redispute && logged_transaction.is_resolved() {
+                    return Err(DepositLogError::CantRedispute.into());
+                }
+                disputed.set_disputed()?;
+                if let Some(amount) = amount {
+                    disputed.set_disputed_amount(amount)?;
+                }
+                disputed.get_disputed_amount()
+            };
+            let available = checked_sub(self.available, disputed_amount)?;
+            let held = checked_add(self.held, disputed_amount)?;
+            *logged_transaction = disputed;
+            self.available = available;
+            self.held = held;
+            self.dispute_count += 1;
+            self.normalize_balances();
             Ok(())
         } else {
             Err(WalletError::InexistentTransaction)
@@ -58,12 +223,21 @@ impl Wallet {
     }
 
     pub fn resolve(&mut self, tx: TransactionId) -> Result<(), WalletError> {
+        self.ensure_unlocked()?;
         if let Some(logged_transaction) = self.deposit_log.get_mut(&tx) {
+            if self.config.idempotent_resolve && logged_transaction.is_resolved() {
+                return Ok(());
+            }
+            let mut resolved = logged_transaction.clone();
             // .set_resolved()? returns early if status != Disputed
-            logged_transaction.set_resolved()?;
-            let disputed_amount = logged_transaction.get_amount();
-            self.available += disputed_amount;
-            self.held -= disputed_amount;
+            resolved.set_resolved()?;
+            let disputed_amount = resolved.get_disputed_amount();
+            let available = checked_add(self.available, disputed_amount)?;
+            let held = release_held(self.held, disputed_amount)?;
+            *logged_transaction = resolved;
+            self.available = available;
+            self.held = held;
+            self.normalize_balances();
             Ok(())
         } else {
             Err(WalletError::InexistentTransaction)
@@ -71,18 +245,111 @@ impl Wallet {
     }
 
     pub fn chargeback(&mut self, tx: TransactionId) -> Result<(), WalletError> {
+        self.ensure_unlocked()?;
         if let Some(logged_transaction) = self.deposit_log.get_mut(&tx) {
+            let mut chargedback = logged_transaction.clone();
             // .set_chargedback()? returns early if status != Disputed
-            logged_transaction.set_chargedback()?;
-            let disputed_amount = logged_transaction.get_amount();
-            self.held -= disputed_amount;
-            self.locked = true;
+            chargedback.set_chargedback()?;
+            let held = release_held(self.held, chargedback.get_disputed_amount())?;
+            *logged_transaction = chargedback;
+            self.held = held;
+            self.lock_reason = Some(LockReason::Chargeback(tx));
+            self.normalize_balances();
             Ok(())
         } else {
             Err(WalletError::InexistentTransaction)
         }
     }
 
+    /// Locks the account without touching the balances. An account already locked keeps
+    /// its original reason.
+    pub fn freeze(&mut self) {
+        self.lock_reason.get_or_insert(LockReason::Frozen);
+    }
+
+    /// Lifts a manual `freeze`, like `unlock`. An account locked by a chargeback stays
+    /// locked and the unfreeze fails.
+    pub fn unfreeze(&mut self) -> Result<Vec<(TransactionId, WalletError)>, WalletError> {
+        match self.lock_reason {
+            Some(LockReason::Chargeback(_)) => Err(WalletError::AccountLocked),
+            Some(LockReason::Frozen) | None => Ok(self.unlock()),
+        }
+    }
+
+    /// Unlocks the account whatever locked it and applies the deposits queued while it was
+    /// locked, in order. A queued deposit that fails doesn't stop the following ones: it's
+    /// dropped and returned with its error.
+    pub fn unlock(&mut self) -> Vec<(TransactionId, WalletError)> {
+        self.lock_reason = None;
+        std::mem::take(&mut self.pending_deposits)
+            .into_iter()
+            .filter_map(|(tx, amount)| self.deposit(tx, amount).err().map(|e| (tx, e)))
+            .collect()
+    }
+
+    /// Whether both wallets have the same balances, locked status and deposit log, which is
+    /// what the `Ledger`'s `PartialEq` compares
+    pub(crate) fn same_state(&self, other: &Wallet) -> bool {
+        self.available == other.available
+            && self.held == other.held
+            && self.lock_reason.is_some() == other.lock_reason.is_some()
+            && self.deposit_log == other.deposit_log
+    }
+
+    /// A locked account rejects every further transaction, only the accessors keep working
+    fn ensure_unlocked(&self) -> Result<(), WalletError> {
+        match self.lock_reason {
+            Some(_) => Err(WalletError::AccountLocked),
+            None => Ok(()),
+        }
+    }
+
+    /// Keeps balances at a canonical scale (no trailing zeros) after every change, so the
+    /// same balance always renders the same whatever the scales of the amounts that made
+    /// it up, e.g. `5.1000 - 0.1` and `5 + 0.0` both give `5`. `Decimal` arithmetic itself
+    /// is exact, only the trailing zeros go.
+    fn normalize_balances(&mut self) {
+        self.available = self.available.normalize();
+        self.held = self.held.normalize();
+    }
+
+    fn queue_pending_deposit(
+        &mut self,
+        tx: TransactionId,
+        amount: Decimal,
+    ) -> Result<(), WalletError> {
+        if !self.config.queue_locked_deposits {
+            return Err(WalletError::AccountLocked);
+        }
+        let is_duplicate = self.deposit_log.contains_key(&tx)
+            || self
+                .pending_deposits
+                .iter()
+                .any(|(pending, _)| *pending == tx);
+        if is_duplicate {
+            return Err(WalletError::DepositIdExists);
+        }
+        self.pending_deposits.push((tx, amount));
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> WalletSnapshot {
+        let (available, held, locked) = self.balance_snapshot();
+        WalletSnapshot {
+            available,
+            held,
+            total: (available + held).normalize(),
+            locked,
+            tx_count: self.tx_count,
+            dispute_count: self.dispute_count,
+        }
+    }
+
+    /// `(available, held, locked)` read together, under a single lock of the wallet
+    pub fn balance_snapshot(&self) -> (Decimal, Decimal, bool) {
+        (self.available, self.held, self.lock_reason.is_some())
+    }
+
     pub fn get_available(&self) -> Decimal {
         self.available
     }
@@ -90,11 +357,104 @@ impl Wallet {
         self.held
     }
     pub fn get_total(&self) -> Decimal {
-        self.available + self.held
+        (self.available + self.held).normalize()
     }
     pub fn get_locked_status(&self) -> bool {
-        self.locked
+        self.lock_reason.is_some()
+    }
+    pub fn get_lock_reason(&self) -> Option<LockReason> {
+        self.lock_reason
+    }
+    /// Whether at least one deposit is currently `Disputed`
+    pub fn has_open_disputes(&self) -> bool {
+        self.deposit_log.values().any(DepositLog::is_disputed)
+    }
+    /// Dispute cycle of the deposit if it's currently `Disputed`
+    pub fn open_dispute_cycle(&self, tx: TransactionId) -> Option<u32> {
+        self.deposit_log
+            .get(&tx)
+            .filter(|deposit| deposit.is_disputed())
+            .map(DepositLog::get_dispute_cycles)
+    }
+    /// Currently `Disputed` deposits with the amount they hold, ordered by tx id
+    pub fn open_disputes(&self) -> Vec<(TransactionId, Decimal)> {
+        let mut disputes: Vec<_> = self
+            .deposit_log
+            .iter()
+            .filter(|(_, deposit)| deposit.is_disputed())
+            .map(|(tx, deposit)| (*tx, deposit.get_disputed_amount()))
+            .collect();
+        disputes.sort_unstable_by_key(|(tx, _)| *tx);
+        disputes
+    }
+    /// Every deposit disputed at least once, with the amount of its latest dispute and
+    /// its current status, ordered by tx id
+    pub fn disputed_deposits(&self) -> Vec<(TransactionId, Decimal, &'static str)> {
+        let mut disputes: Vec<_> = self
+            .deposit_log
+            .iter()
+            .filter(|(_, deposit)| deposit.was_disputed())
+            .map(|(tx, deposit)| (*tx, deposit.get_disputed_amount(), deposit.status().label()))
+            .collect();
+        disputes.sort_unstable_by_key(|(tx, ..)| *tx);
+        disputes
+    }
+    /// Rough heap and inline size of the wallet, counting the allocated capacity of its
+    /// deposit log and pending deposits rather than their length
+    pub fn approximate_memory_bytes(&self) -> usize {
+        std::mem::size_of::<Self>() + self.approximate_heap_bytes()
     }
+    /// The heap part of `approximate_memory_bytes`, for containers already counting the
+    /// wallet inline
+    pub(crate) fn approximate_heap_bytes(&self) -> usize {
+        self.deposit_log.capacity() * std::mem::size_of::<(TransactionId, DepositLog)>()
+            + self.pending_deposits.capacity() * std::mem::size_of::<(TransactionId, Decimal)>()
+    }
+    /// Status of the deposit `tx`, `None` if there's no such deposit
+    pub fn deposit_status(&self, tx: TransactionId) -> Option<DepositStatus> {
+        self.deposit_log.get(&tx).map(DepositLog::status)
+    }
+    /// Number of successful disputes, resolving a dispute doesn't decrease it
+    pub fn get_dispute_count(&self) -> u64 {
+        self.dispute_count
+    }
+    /// Number of applied deposits and withdrawals
+    pub fn get_tx_count(&self) -> u64 {
+        self.tx_count
+    }
+}
+
+/// Rejects negative deposits and those below the `EngineConfig::min_deposit`, also used by
+/// the `Ledger` so an invalid deposit doesn't create the client's wallet. Zero is accepted,
+/// it's what a blank amount reads as with `StreamConfig::blank_amount_as_zero`.
+pub(crate) fn ensure_valid_deposit(
+    config: &EngineConfig,
+    amount: Decimal,
+) -> Result<(), WalletError> {
+    if amount < Decimal::ZERO {
+        return Err(WalletError::InvalidAmount(amount));
+    }
+    match config.min_deposit {
+        Some(min_deposit) if amount < min_deposit => Err(WalletError::DepositBelowMinimum(amount)),
+        _ => Ok(()),
+    }
+}
+
+fn checked_add(balance: Decimal, amount: Decimal) -> Result<Decimal, WalletError> {
+    balance.checked_add(amount).ok_or(WalletError::Overflow)
+}
+
+fn checked_sub(balance: Decimal, amount: Decimal) -> Result<Decimal, WalletError> {
+    balance.checked_sub(amount).ok_or(WalletError::Overflow)
+}
+
+/// Funds held by a dispute can only be released if they are actually held
+fn release_held(held: Decimal, amount: Decimal) -> Result<Decimal, WalletError> {
+    let held = checked_sub(held, amount)?;
+    if held < Decimal::ZERO {
+        return Err(WalletError::InvariantViolation);
+    }
+    Ok(held)
 }
 
 #[cfg(test)]
@@ -112,6 +472,7 @@ mod tests {
         let expected = Wallet {
             available: dec!(10),
             deposit_log: HashMap::from([(1, deposit)]),
+            tx_count: 1,
             ..Default::default()
         };
 
@@ -129,6 +490,7 @@ mod tests {
         let expected = Wallet {
             available: dec!(1),
             deposit_log: HashMap::from([(1, deposit1)]),
+            tx_count: 1,
             ..Default::default()
         };
 
@@ -136,6 +498,20 @@ mod tests {
         assert_eq!(wallet, expected);
     }
 
+    #[test]
+    fn test_deposit_overflowing_the_balance_fails_and_leaves_it_unchanged() {
+        let mut wallet = Wallet::default();
+        let amount = Decimal::MAX - dec!(1);
+        wallet.deposit(1, amount).unwrap();
+
+        assert_eq!(wallet.deposit(2, amount), Err(WalletError::Overflow));
+        assert_eq!(wallet.get_available(), amount);
+        assert_eq!(wallet.get_tx_count(), 1);
+        // tx 2 wasn't logged
+        wallet.deposit(2, dec!(1)).unwrap();
+        assert_eq!(wallet.get_available(), Decimal::MAX);
+    }
+
     #[test]
     fn test_withdraw_works_with_sufficient_funds() {
         let mut wallet = Wallet::default();
@@ -146,6 +522,7 @@ mod tests {
         let expected = Wallet {
             available: dec!(5),
             deposit_log: HashMap::from([(1, deposit)]),
+            tx_count: 2,
             ..Default::default()
         };
 
@@ -162,6 +539,7 @@ mod tests {
         let expected = Wallet {
             available: dec!(10),
             deposit_log: HashMap::from([(1, deposit)]),
+            tx_count: 1,
             ..Default::default()
         };
         assert_eq!(result, Err(WalletError::InsufficientFunds));
@@ -179,6 +557,7 @@ mod tests {
         let expected = Wallet {
             available: dec!(15),
             deposit_log: HashMap::from([(1, deposit.clone()), (2, deposit_to_be_disputed.clone())]),
+            tx_count: 2,
             ..Default::default()
         };
         assert_eq!(wallet, expected);
@@ -190,6 +569,8 @@ mod tests {
             available: dec!(10),
             held: dec!(5),
             deposit_log: HashMap::from([(1, deposit), (2, deposit_to_be_disputed)]),
+            tx_count: 2,
+            dispute_count: 1,
             ..Default::default()
         };
 
@@ -208,6 +589,8 @@ mod tests {
         let expected = Wallet {
             available: dec!(10),
             deposit_log: HashMap::from([(1, deposit)]),
+            tx_count: 1,
+            dispute_count: 1,
             ..Default::default()
         };
         assert_eq!(wallet, expected);
@@ -225,9 +608,528 @@ mod tests {
         let expected = Wallet {
             available: dec!(0),
             held: dec!(0),
-            locked: true,
+            lock_reason: Some(LockReason::Chargeback(1)),
             deposit_log: HashMap::from([(1, deposit)]),
+            tx_count: 1,
+            dispute_count: 1,
+            ..Default::default()
         };
         assert_eq!(wallet, expected);
     }
+
+    #[test]
+    fn test_resolved_deposit_can_be_disputed_again_and_charged_back() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.deposit(2, dec!(2)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.resolve(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(12));
+
+        wallet.dispute(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(2));
+        assert_eq!(wallet.get_held(), dec!(10));
+
+        wallet.chargeback(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(2));
+        assert_eq!(wallet.get_held(), dec!(0));
+        assert_eq!(wallet.get_total(), dec!(2));
+        assert!(wallet.get_locked_status());
+    }
+
+    #[test]
+    fn test_dispute_count_keeps_growing_over_dispute_resolve_cycles() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.deposit(2, dec!(5)).unwrap();
+        for _ in 0..3 {
+            wallet.dispute(1).unwrap();
+            wallet.resolve(1).unwrap();
+        }
+        wallet.dispute(2).unwrap();
+        // failed disputes aren't counted
+        assert!(wallet.dispute(2).is_err());
+        assert!(wallet.dispute(3).is_err());
+
+        assert_eq!(wallet.get_dispute_count(), 4);
+        assert_eq!(wallet.snapshot().dispute_count, 4);
+    }
+
+    #[test]
+    fn test_redispute_is_rejected_when_forbidden() {
+        let config = EngineConfig {
+            forbid_redispute: true,
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.resolve(1).unwrap();
+
+        assert_eq!(
+            wallet.dispute(1),
+            Err(WalletError::DepositLogError(DepositLogError::CantRedispute))
+        );
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+    }
+
+    #[test]
+    fn test_dispute_fails_after_reaching_dispute_cycle_limit() {
+        let config = EngineConfig {
+            max_dispute_cycles: Some(2),
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.resolve(1).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.resolve(1).unwrap();
+
+        let result = wallet.dispute(1);
+
+        assert_eq!(result, Err(WalletError::DisputeChurnLimit));
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+    }
+
+    #[test]
+    fn test_deposit_to_locked_account_is_rejected_by_default() {
+        let mut wallet = Wallet {
+            lock_reason: Some(LockReason::Chargeback(1)),
+            ..Default::default()
+        };
+
+        let result = wallet.deposit(1, dec!(10));
+
+        assert_eq!(result, Err(WalletError::AccountLocked));
+        assert_eq!(wallet.get_available(), dec!(0));
+    }
+
+    #[test]
+    fn test_dispute_on_locked_account_is_rejected() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.deposit(2, dec!(5)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.chargeback(1).unwrap();
+        let locked = wallet.clone();
+
+        assert_eq!(wallet.dispute(2), Err(WalletError::AccountLocked));
+        assert_eq!(wallet.withdraw(3, dec!(1)), Err(WalletError::AccountLocked));
+        assert_eq!(wallet.resolve(1), Err(WalletError::AccountLocked));
+        assert_eq!(wallet.chargeback(1), Err(WalletError::AccountLocked));
+        assert_eq!(wallet, locked);
+        assert_eq!(wallet.get_available(), dec!(5));
+        assert!(wallet.get_locked_status());
+    }
+
+    #[test]
+    fn test_deposit_to_locked_account_is_queued_and_applied_on_unfreeze() {
+        let config = EngineConfig {
+            queue_locked_deposits: true,
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.chargeback(1).unwrap();
+
+        wallet.deposit(2, dec!(5)).unwrap();
+        wallet.deposit(3, dec!(1)).unwrap();
+        assert_eq!(wallet.get_available(), dec!(0));
+        assert_eq!(
+            wallet.deposit(2, dec!(5)),
+            Err(WalletError::DepositIdExists)
+        );
+
+        assert_eq!(wallet.unfreeze(), Err(WalletError::AccountLocked));
+        assert!(wallet.get_locked_status());
+        assert_eq!(wallet.unlock(), []);
+
+        assert!(!wallet.get_locked_status());
+        assert_eq!(wallet.get_available(), dec!(6));
+        assert!(wallet.pending_deposits.is_empty());
+    }
+
+    #[test]
+    fn test_failing_queued_deposit_doesnt_drop_the_following_ones() {
+        let config = EngineConfig {
+            queue_locked_deposits: true,
+            max_client_balance: Some(dec!(10)),
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(4)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.chargeback(1).unwrap();
+        wallet.deposit(2, dec!(11)).unwrap();
+        wallet.deposit(3, dec!(2)).unwrap();
+
+        let failed = wallet.unlock();
+
+        assert_eq!(failed, [(2, WalletError::BalanceCeilingExceeded)]);
+        assert_eq!(wallet.get_available(), dec!(2));
+        assert!(wallet.pending_deposits.is_empty());
+    }
+
+    #[test]
+    fn test_freeze_locks_without_touching_balances_until_unfreeze() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.deposit(2, dec!(3)).unwrap();
+        wallet.dispute(2).unwrap();
+        let before = wallet.snapshot();
+
+        wallet.freeze();
+
+        assert_eq!(wallet.get_lock_reason(), Some(LockReason::Frozen));
+        assert_eq!(
+            wallet.snapshot(),
+            WalletSnapshot {
+                locked: true,
+                ..before
+            }
+        );
+        assert_eq!(wallet.withdraw(3, dec!(1)), Err(WalletError::AccountLocked));
+        assert_eq!(wallet.resolve(2), Err(WalletError::AccountLocked));
+
+        assert_eq!(wallet.unfreeze(), Ok(vec![]));
+        assert_eq!(wallet.snapshot(), before);
+        wallet.resolve(2).unwrap();
+        assert_eq!(wallet.get_available(), dec!(13));
+    }
+
+    #[test]
+    fn test_freeze_keeps_the_chargeback_lock_reason() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.chargeback(1).unwrap();
+
+        wallet.freeze();
+
+        assert_eq!(wallet.get_lock_reason(), Some(LockReason::Chargeback(1)));
+        assert_eq!(wallet.unfreeze(), Err(WalletError::AccountLocked));
+        assert_eq!(wallet.get_lock_reason(), Some(LockReason::Chargeback(1)));
+    }
+
+    #[test]
+    fn test_second_resolve_fails_by_default() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.resolve(1).unwrap();
+
+        let result = wallet.resolve(1);
+
+        assert_eq!(
+            result,
+            Err(WalletError::DepositLogError(
+                DepositLogError::CantResolveUndisputed
+            ))
+        );
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+    }
+
+    #[test]
+    fn test_second_resolve_is_a_no_op_when_idempotent() {
+        let config = EngineConfig {
+            idempotent_resolve: true,
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.resolve(1).unwrap();
+
+        wallet.resolve(1).unwrap();
+
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+    }
+
+    #[test]
+    fn test_withdraw_of_entire_available_balance_leaves_exactly_zero() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10.1234)).unwrap();
+
+        wallet.withdraw(2, dec!(10.1234)).unwrap();
+
+        assert_eq!(wallet.get_available(), Decimal::ZERO);
+        assert!(wallet.get_available().is_zero());
+        assert_eq!(wallet.get_total(), Decimal::ZERO);
+    }
+
+    #[test]
+    fn test_withdraw_fails_when_leaving_less_than_minimum_balance() {
+        let config = EngineConfig {
+            require_minimum_balance: Some(dec!(5)),
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(10)).unwrap();
+
+        let result = wallet.withdraw(2, dec!(6));
+        assert_eq!(result, Err(WalletError::BelowMinimumBalance));
+        assert_eq!(wallet.get_available(), dec!(10));
+
+        wallet.withdraw(3, dec!(5)).unwrap();
+        assert_eq!(wallet.get_available(), dec!(5));
+    }
+
+    #[test]
+    fn test_balance_snapshot_matches_accessors() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.deposit(2, dec!(2.5)).unwrap();
+        wallet.dispute(1).unwrap();
+        wallet.chargeback(1).unwrap();
+
+        assert_eq!(
+            wallet.balance_snapshot(),
+            (
+                wallet.get_available(),
+                wallet.get_held(),
+                wallet.get_locked_status()
+            )
+        );
+        assert_eq!(wallet.balance_snapshot(), (dec!(2.5), dec!(0), true));
+    }
+
+    #[test]
+    fn test_deposit_fails_when_exceeding_balance_ceiling() {
+        let config = EngineConfig {
+            max_client_balance: Some(dec!(10)),
+            ..Default::default()
+        };
+        let mut wallet = Wallet::new(Arc::new(config));
+        wallet.deposit(1, dec!(6)).unwrap();
+        wallet.dispute(1).unwrap();
+
+        // held funds count towards the ceiling
+        let result = wallet.deposit(2, dec!(4.0001));
+        assert_eq!(result, Err(WalletError::BalanceCeilingExceeded));
+        assert_eq!(wallet.get_total(), dec!(6));
+
+        wallet.deposit(3, dec!(4)).unwrap();
+        assert_eq!(wallet.get_total(), dec!(10));
+        // the rejected tx id wasn't recorded
+        wallet.withdraw(4, dec!(4)).unwrap();
+        wallet.deposit(2, dec!(4)).unwrap();
+    }
+
+    #[test]
+    fn test_dispute_of_zero_amount_deposit_follows_policy() {
+        let wallet_with = |zero_amount_disputes| {
+            let config = EngineConfig {
+                zero_amount_disputes,
+                ..Default::default()
+            };
+            let mut wallet = Wallet::new(Arc::new(config));
+            wallet.deposit(1, dec!(0)).unwrap();
+            wallet
+        };
+
+        let mut wallet = wallet_with(ZeroAmountDisputes::Allow);
+        wallet.dispute(1).unwrap();
+        assert_eq!(wallet.resolve(1), Ok(()));
+
+        let mut wallet = wallet_with(ZeroAmountDisputes::Reject);
+        assert_eq!(wallet.dispute(1), Err(WalletError::ZeroAmountDispute));
+
+        let mut wallet = wallet_with(ZeroAmountDisputes::Ignore);
+        let before = wallet.clone();
+        assert_eq!(wallet.dispute(1), Ok(()));
+        assert_eq!(wallet, before);
+        // the deposit never got disputed
+        assert!(wallet.resolve(1).is_err());
+    }
+
+    #[test]
+    fn test_partial_dispute_holds_only_the_disputed_amount() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+
+        wallet.dispute_partial(1, dec!(4)).unwrap();
+        assert_eq!(wallet.get_available(), dec!(6));
+        assert_eq!(wallet.get_held(), dec!(4));
+
+        wallet.chargeback(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(6));
+        assert_eq!(wallet.get_held(), dec!(0));
+        assert!(wallet.get_locked_status());
+    }
+
+    #[test]
+    fn test_partial_dispute_then_rest_of_the_deposit() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+
+        wallet.dispute_partial(1, dec!(5)).unwrap();
+        wallet.resolve(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+
+        // disputing the other half after the first one got resolved
+        wallet.dispute_partial(1, dec!(5)).unwrap();
+        assert_eq!(wallet.get_available(), dec!(5));
+        assert_eq!(wallet.get_held(), dec!(5));
+        // a dispute without amount holds the rest while the deposit is disputed
+        wallet.dispute(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(0));
+        assert_eq!(wallet.get_held(), dec!(10));
+        assert_eq!(wallet.dispute(1), Err(WalletError::AlreadyDisputed));
+
+        wallet.resolve(1).unwrap();
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+    }
+
+    #[test]
+    fn test_partial_dispute_fails_for_amount_exceeding_deposit() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+
+        let result = wallet.dispute_partial(1, dec!(11));
+
+        assert_eq!(
+            result,
+            Err(WalletError::DepositLogError(
+                DepositLogError::InvalidDisputedAmount
+            ))
+        );
+        assert_eq!(wallet.get_available(), dec!(10));
+        assert_eq!(wallet.get_held(), dec!(0));
+        // the deposit is still disputable
+        wallet.dispute(1).unwrap();
+    }
+
+    #[test]
+    fn test_deposit_status_follows_the_dispute_lifecycle() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.withdraw(2, dec!(1)).unwrap();
+        assert_eq!(wallet.deposit_status(1), Some(DepositStatus::New));
+        assert_eq!(wallet.deposit_status(2), None);
+
+        wallet.dispute(1).unwrap();
+        assert_eq!(wallet.deposit_status(1), Some(DepositStatus::Disputed));
+        wallet.resolve(1).unwrap();
+        assert_eq!(wallet.deposit_status(1), Some(DepositStatus::Resolved));
+        wallet.dispute(1).unwrap();
+        assert_eq!(wallet.deposit_status(1), Some(DepositStatus::Disputed));
+        wallet.chargeback(1).unwrap();
+        assert_eq!(wallet.deposit_status(1), Some(DepositStatus::Chargedback));
+    }
+
+    #[test]
+    fn test_double_dispute_is_already_disputed_not_inexistent() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.dispute(1).unwrap();
+
+        assert_eq!(wallet.dispute(1), Err(WalletError::AlreadyDisputed));
+        assert_eq!(wallet.dispute(2), Err(WalletError::InexistentTransaction));
+        assert_eq!(wallet.get_held(), dec!(10));
+        // re-disputing is fine again once resolved
+        wallet.resolve(1).unwrap();
+        wallet.dispute(1).unwrap();
+    }
+
+    #[test]
+    fn test_releasing_more_than_held_is_an_invariant_violation() {
+        let mut deposit = DepositLog::new(dec!(10));
+        deposit.set_disputed().unwrap();
+        // held lost track of the dispute, e.g. restored from a corrupted snapshot
+        let wallet = Wallet {
+            available: dec!(8),
+            held: dec!(2),
+            deposit_log: HashMap::from([(1, deposit)]),
+            tx_count: 1,
+            dispute_count: 1,
+            ..Default::default()
+        };
+
+        let mut resolved = wallet.clone();
+        assert_eq!(resolved.resolve(1), Err(WalletError::InvariantViolation));
+        assert_eq!(resolved, wallet);
+        let mut chargedback = wallet.clone();
+        assert_eq!(
+            chargedback.chargeback(1),
+            Err(WalletError::InvariantViolation)
+        );
+        assert_eq!(chargedback, wallet);
+    }
+
+    #[test]
+    fn test_partial_disputes_never_leave_negative_balances() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(10)).unwrap();
+        wallet.deposit(2, dec!(0.0003)).unwrap();
+        let check = |wallet: &Wallet| {
+            assert!(wallet.get_held() >= dec!(0));
+            assert!(wallet.get_total() >= dec!(0));
+        };
+
+        wallet.dispute_partial(1, dec!(3)).unwrap();
+        check(&wallet);
+        wallet.dispute(2).unwrap();
+        check(&wallet);
+        wallet.dispute_partial(1, dec!(7)).unwrap();
+        check(&wallet);
+        wallet.resolve(1).unwrap();
+        check(&wallet);
+        wallet.chargeback(2).unwrap();
+        check(&wallet);
+        assert_eq!(wallet.get_held(), dec!(0));
+        assert_eq!(wallet.get_total(), dec!(10));
+    }
+
+    #[test]
+    fn test_arithmetic_across_scales_is_exact_and_canonical() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(5)).unwrap();
+        wallet.withdraw(2, dec!(0.0003)).unwrap();
+        assert_eq!(wallet.get_available().to_string(), "4.9997");
+
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(5.1234)).unwrap();
+        wallet.withdraw(2, dec!(0.0003)).unwrap();
+        assert_eq!(wallet.get_available().to_string(), "5.1231");
+
+        // the same balance renders the same, whatever scales made it up
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(5.1000)).unwrap();
+        assert_eq!(wallet.get_available().to_string(), "5.1");
+        wallet.withdraw(2, dec!(0.1)).unwrap();
+        assert_eq!(wallet.get_available().to_string(), "5");
+        wallet.deposit(3, dec!(0.0000)).unwrap();
+        assert_eq!(wallet.get_available().to_string(), "5");
+        wallet.dispute(1).unwrap();
+        assert_eq!(wallet.get_available().to_string(), "-0.1");
+        assert_eq!(wallet.get_held().to_string(), "5.1");
+        assert_eq!(wallet.get_total().to_string(), "5");
+    }
+
+    #[test]
+    fn test_many_tiny_dispute_resolve_cycles_keep_exact_balances() {
+        let mut wallet = Wallet::default();
+        wallet.deposit(1, dec!(0.0001)).unwrap();
+        wallet.deposit(2, dec!(1.5000)).unwrap();
+
+        for _ in 0..5000 {
+            wallet.dispute(1).unwrap();
+            wallet.resolve(1).unwrap();
+            wallet.dispute(2).unwrap();
+            wallet.resolve(2).unwrap();
+        }
+
+        assert_eq!(wallet.get_available(), dec!(1.5001));
+        assert_eq!(wallet.get_available().to_string(), "1.5001");
+        assert_eq!(wallet.get_held().to_string(), "0");
+        assert_eq!(wallet.get_total().to_string(), "1.5001");
+    }
 }
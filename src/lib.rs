@@ -1,3 +1,4 @@
+pub mod cli;
 pub mod csv;
 pub mod domain;
 
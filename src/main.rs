@@ -1,33 +1,41 @@
 use std::sync::Arc;
 
-use anyhow::{anyhow, Context};
+use anyhow::Context;
 
+use payments_engine::cli::{CliArgs, OutputStream};
 use payments_engine::domain::Ledger;
-use payments_engine::run_csv_stream::run;
+use payments_engine::run_csv_stream::{open_input, run_many};
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let mut args = std::env::args();
-    let file_name = args.nth(1).ok_or(anyhow!("Input file not provided"))?;
-    let input = tokio::fs::File::open(&file_name)
-        .await
-        .context(format!("Can't open input file: `{}`", file_name))?;
-    setup_tracing();
+    let args = CliArgs::parse(std::env::args().skip(1))?;
+    let mut inputs = Vec::with_capacity(args.inputs.len());
+    for file_name in &args.inputs {
+        inputs.push(open_input(file_name).await?);
+    }
+    setup_tracing(args.routing.logs);
 
     let ledger = Arc::new(Ledger::new());
-    run(input, ledger.clone()).await;
+    run_many(inputs, ledger.clone())
+        .await
+        .with_context(|| format!("Processing `{}` failed", args.inputs.join("`, `")))?;
 
-    let mut output = std::io::stdout().lock();
-    ledger.dump_to_writer(&mut output)?;
+    args.write_dump(
+        &ledger,
+        &mut std::io::stdout().lock(),
+        &mut std::io::stderr().lock(),
+    )?;
     Ok(())
 }
 
-fn setup_tracing() {
+fn setup_tracing(logs: OutputStream) {
     if std::env::var("RUST_LOG").is_err() {
         std::env::set_var("RUST_LOG", "error")
     }
-    tracing_subscriber::fmt::fmt()
-        .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .init();
+    let subscriber = tracing_subscriber::fmt::fmt()
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match logs {
+        OutputStream::Stdout => subscriber.with_writer(std::io::stdout).init(),
+        OutputStream::Stderr => subscriber.with_writer(std::io::stderr).init(),
+    }
 }
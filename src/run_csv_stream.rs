@@ -1,42 +1,716 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tracing::warn;
 
-use crate::csv::create_transaction_stream;
-use crate::domain::Ledger;
+use crate::csv::{create_numbered_transaction_stream, StreamConfig, TransactionRecordError};
+use crate::domain::{
+    ClientDelta, ClientId, Ledger, LedgerError, RejectionKind, Transaction, TransactionId,
+    WalletError, WalletStore,
+};
+
+/// Failure of a whole run, as opposed to the rejection of a single transaction
+#[derive(thiserror::Error, Debug)]
+pub enum RunError {
+    #[error("Can't open input file `{path}`: {source}")]
+    OpenInput {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Io error while reading input: {0}")]
+    Io(std::io::Error),
+    #[error("Input is not valid UTF-8 on line {line}")]
+    Utf8 { line: u64 },
+    #[error("Aborted on line {line}: {reason}")]
+    StrictAbort { line: u64, reason: String },
+    #[error("Run timed out after {0:?}")]
+    Timeout(Duration),
+    #[error("More than {0} transactions failed")]
+    ErrorThresholdExceeded(usize),
+}
+
+#[derive(Debug)]
+pub enum TransactionOutcome {
+    Applied,
+    Rejected(LedgerError),
+}
+
+impl From<Result<(), LedgerError>> for TransactionOutcome {
+    fn from(result: Result<(), LedgerError>) -> Self {
+        match result {
+            Ok(()) => TransactionOutcome::Applied,
+            Err(e) => TransactionOutcome::Rejected(e),
+        }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct RunConfig {
+    /// Process every transaction on a separate tokio task (still `.await`ed in order).
+    /// Off by default: `Ledger::process_transaction` is synchronous, so spawning only adds
+    /// overhead. Useful to simulate access to the ledger from a different thread.
+    pub spawn_tasks: bool,
+    /// Skip a transaction identical to the one right before it (same type, client, tx
+    /// and amount), as emitted by some flaky upstreams.
+    pub skip_consecutive_duplicates: bool,
+    /// Abort on the first record that can't be parsed or gets rejected by the ledger.
+    pub strict: bool,
+    /// Abort once more than this many records failed to parse or got rejected.
+    pub max_errors: Option<usize>,
+    /// Abort if the whole input isn't processed within this duration.
+    pub timeout: Option<Duration>,
+    pub stream: StreamConfig,
+}
+
+/// Why the ledger rejected a transaction. Unlike `LedgerError` it can be compared and cloned.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RejectionReason {
+    InexistentClient,
+    Wallet(WalletError),
+    DuplicateTransactionId,
+    /// The referenced transaction belongs to the `expected` client
+    ClientMismatch {
+        expected: ClientId,
+    },
+    Other(String),
+}
+
+impl From<LedgerError> for RejectionReason {
+    fn from(e: LedgerError) -> Self {
+        match e {
+            LedgerError::InexistentClient(_) => RejectionReason::InexistentClient,
+            LedgerError::WalletError(e) => RejectionReason::Wallet(e),
+            LedgerError::DuplicateTransactionId(_) => RejectionReason::DuplicateTransactionId,
+            LedgerError::ClientMismatch { expected, .. } => {
+                RejectionReason::ClientMismatch { expected }
+            }
+            e => RejectionReason::Other(e.to_string()),
+        }
+    }
+}
+
+impl RejectionReason {
+    pub fn kind(&self) -> RejectionKind {
+        match self {
+            RejectionReason::InexistentClient => RejectionKind::InexistentClient,
+            RejectionReason::Wallet(e) => RejectionKind::of_wallet_error(e),
+            RejectionReason::DuplicateTransactionId => RejectionKind::DuplicateTransactionId,
+            RejectionReason::ClientMismatch { .. } => RejectionKind::ClientMismatch,
+            RejectionReason::Other(_) => RejectionKind::Other,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Rejection {
+    pub line: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub reason: RejectionReason,
+}
+
+/// Counts of what happened to the records of a run
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct RunReport {
+    pub applied: usize,
+    pub rejected: usize,
+    pub parse_errors: usize,
+    pub skipped_duplicate: usize,
+    /// One entry per `rejected` transaction, in input order
+    pub rejections: Vec<Rejection>,
+}
+
+impl RunReport {
+    /// Records read from the input, whatever happened to them
+    pub fn read(&self) -> usize {
+        self.applied + self.rejected + self.parse_errors + self.skipped_duplicate
+    }
+
+    /// Number of `rejections` of each kind, in order of first occurrence
+    pub fn rejected_by_kind(&self) -> Vec<(RejectionKind, usize)> {
+        let mut counts: Vec<(RejectionKind, usize)> = Vec::new();
+        for rejection in &self.rejections {
+            let kind = rejection.reason.kind();
+            match counts.iter_mut().find(|(k, _)| *k == kind) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((kind, 1)),
+            }
+        }
+        counts
+    }
+
+    /// Adds the counts of `other`, a run of the input following this one
+    fn merge(&mut self, other: RunReport) {
+        self.applied += other.applied;
+        self.rejected += other.rejected;
+        self.parse_errors += other.parse_errors;
+        self.skipped_duplicate += other.skipped_duplicate;
+        self.rejections.extend(other.rejections);
+    }
+
+    fn reject(&mut self, line: u64, client: ClientId, tx: TransactionId, reason: RejectionReason) {
+        self.rejected += 1;
+        self.rejections.push(Rejection {
+            line,
+            client,
+            tx,
+            reason,
+        });
+    }
+}
+
+/// One-line summary, as logged at the end of `run_with_config`
+impl std::fmt::Display for RunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "read {} records: {} applied, {} rejected",
+            self.read(),
+            self.applied,
+            self.rejected
+        )?;
+        let by_kind = self.rejected_by_kind();
+        if !by_kind.is_empty() {
+            let by_kind: Vec<_> = by_kind
+                .iter()
+                .map(|(kind, count)| format!("{}: {count}", kind.label()))
+                .collect();
+            write!(f, " ({})", by_kind.join(", "))?;
+        }
+        write!(
+            f,
+            ", {} parse errors, {} skipped duplicates",
+            self.parse_errors, self.skipped_duplicate
+        )
+    }
+}
 
 pub async fn run<R>(reader: R, ledger: Arc<Ledger>)
 where
     R: tokio::io::AsyncRead + Unpin + Send + 'static,
 {
-    let mut transaction_stream = create_transaction_stream(reader).await;
+    run_collecting(reader, ledger).await;
+}
 
-    while let Some(transaction_result) = transaction_stream.next().await {
+/// Like `run`, but returns the report with every rejected transaction and why
+pub async fn run_collecting<R>(reader: R, ledger: Arc<Ledger>) -> RunReport
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    run_with_config(reader, ledger, RunConfig::default()).await
+}
+
+/// Like `try_run_with_config`, but the failure of the run is only logged.
+/// Returns the counts up to the point where the run stopped, also logged as a summary.
+pub async fn run_with_config<R>(reader: R, ledger: Arc<Ledger>, config: RunConfig) -> RunReport
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let mut report = RunReport::default();
+    if let Err(e) = process_stream(reader, ledger, &config, &mut report).await {
+        warn!("Run stopped: {e}");
+    }
+    warn!("Run summary: {report}");
+    report
+}
+
+/// Like `run`, but tells a completed run from one aborted by the input failing to be
+/// read: that's an `Err`, while rejected and malformed records are only counted in the report.
+pub async fn try_run<R>(reader: R, ledger: Arc<Ledger>) -> Result<RunReport, RunError>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    try_run_with_config(reader, ledger, RunConfig::default()).await
+}
+
+/// Processes the whole input, failing on IO errors and on the aborts set up in `config`.
+/// Transactions applied before the failure stay applied.
+pub async fn try_run_with_config<R>(
+    reader: R,
+    ledger: Arc<Ledger>,
+    config: RunConfig,
+) -> Result<RunReport, RunError>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let mut report = RunReport::default();
+    let processing = process_stream(reader, ledger, &config, &mut report);
+    match config.timeout {
+        Some(timeout) => tokio::time::timeout(timeout, processing)
+            .await
+            .map_err(|_| RunError::Timeout(timeout))??,
+        None => processing.await?,
+    }
+    Ok(report)
+}
+
+/// Like `try_run`, processing `readers` one after the other into the same `ledger`.
+/// Stops at the first failing input; the lines of the `rejections` are per input.
+pub async fn run_many<I, R>(readers: I, ledger: Arc<Ledger>) -> Result<RunReport, RunError>
+where
+    I: IntoIterator<Item = R>,
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let mut report = RunReport::default();
+    for reader in readers {
+        report.merge(try_run(reader, ledger.clone()).await?);
+    }
+    Ok(report)
+}
+
+/// Blocking version of `try_run` for callers without a tokio runtime. The whole input is
+/// read into memory first, then processed on a runtime created for this call.
+/// Panics if called from within an async context.
+pub fn run_sync<R>(mut reader: R, ledger: Arc<Ledger>) -> Result<RunReport, RunError>
+where
+    R: std::io::Read,
+{
+    let mut input = Vec::new();
+    reader.read_to_end(&mut input).map_err(RunError::Io)?;
+    tokio::runtime::Builder::new_current_thread()
+        .build()
+        .map_err(RunError::Io)?
+        .block_on(try_run(std::io::Cursor::new(input), ledger))
+}
+
+pub async fn open_input(path: &str) -> Result<tokio::fs::File, RunError> {
+    tokio::fs::File::open(path)
+        .await
+        .map_err(|source| RunError::OpenInput {
+            path: path.to_string(),
+            source,
+        })
+}
+
+async fn process_stream<R>(
+    reader: R,
+    ledger: Arc<Ledger>,
+    config: &RunConfig,
+    report: &mut RunReport,
+) -> Result<(), RunError>
+where
+    R: tokio::io::AsyncRead + Unpin + Send + 'static,
+{
+    let mut previous_transaction = None;
+    let mut transaction_stream =
+        create_numbered_transaction_stream(reader, config.stream.clone()).await;
+
+    while let Some((line, transaction_result)) = transaction_stream.next().await {
         match transaction_result {
             Ok(transaction) => {
+                if config.skip_consecutive_duplicates {
+                    if previous_transaction.as_ref() == Some(&transaction) {
+                        report.skipped_duplicate += 1;
+                        continue;
+                    }
+                    previous_transaction = Some(transaction.clone());
+                }
                 let tx = transaction.get_transaction_id();
                 let client = transaction.get_client_id();
-                let ledger = ledger.clone();
-                // Spawn a different taks to simulate access to ledger from a differnt thread
-                // but still .await it so we have deterministic results for the synchronous test
-                // coming form stdin.
-                let result =
+                let ledger_result = if config.spawn_tasks {
+                    let ledger = ledger.clone();
+                    // Spawn a different taks to simulate access to ledger from a differnt thread
+                    // but still .await it so we have deterministic results for the synchronous test
+                    // coming form stdin.
                     tokio::task::spawn(async move { ledger.process_transaction(transaction) })
-                        .await;
+                        .await
+                        .map_err(|e| format!("Join error: {e}"))
+                } else {
+                    Ok(ledger.process_transaction(transaction))
+                };
 
-                match result {
-                    Ok(ledger_result) => {
-                        if let Err(e) = ledger_result {
-                            warn!(client, tx, "Error processing transaction: {e}")
+                match ledger_result {
+                    Ok(Ok(())) => report.applied += 1,
+                    Ok(Err(e)) => {
+                        warn!(line, client, tx, "Error processing transaction: {e}");
+                        let reason = e.to_string();
+                        report.reject(line, client, tx, e.into());
+                        if config.strict {
+                            return Err(RunError::StrictAbort { line, reason });
                         }
                     }
-                    Err(e) => {
-                        warn!("Join error: {e}");
+                    Err(reason) => {
+                        warn!("{reason}");
+                        report.reject(line, client, tx, RejectionReason::Other(reason.clone()));
+                        if config.strict {
+                            return Err(RunError::StrictAbort { line, reason });
+                        }
                     }
                 }
             }
-            Err(e) => warn!(?e, "Error in transaction stream"),
+            Err(e) => {
+                warn!(line, ?e, "Error in transaction stream");
+                report.parse_errors += 1;
+                if let Some(run_error) = fatal_record_error(line, e, config.strict) {
+                    return Err(run_error);
+                }
+            }
+        }
+        if let Some(max_errors) = config.max_errors {
+            if report.rejected + report.parse_errors > max_errors {
+                return Err(RunError::ErrorThresholdExceeded(max_errors));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// IO errors always end the run, any other record error only does in `strict` mode
+fn fatal_record_error(line: u64, e: TransactionRecordError, strict: bool) -> Option<RunError> {
+    match e {
+        TransactionRecordError::CsvError(e) => {
+            let reason = e.to_string();
+            match e.into_kind() {
+                csv_async::ErrorKind::Io(e) => Some(RunError::Io(e)),
+                csv_async::ErrorKind::Utf8 { .. } if strict => Some(RunError::Utf8 { line }),
+                _ => strict.then_some(RunError::StrictAbort { line, reason }),
+            }
+        }
+        TransactionRecordError::IoError(e) => Some(RunError::Io(e)),
+        e => strict.then(|| RunError::StrictAbort {
+            line,
+            reason: e.to_string(),
+        }),
+    }
+}
+
+/// Processes the transactions on `workers` tasks, each owning the clients with
+/// `client % workers` equal to its index, so distinct clients are processed concurrently
+/// while the transactions of a single client keep their input order.
+/// Records that can't be parsed are logged and skipped.
+///
+/// The order across clients isn't kept: when clients on different workers reuse the same
+/// tx id, whichever gets processed first owns it and the other is rejected as a
+/// `DuplicateTransactionId`, which isn't necessarily the one first in the input.
+pub async fn run_parallel<R>(reader: R, ledger: Arc<Ledger>, workers: usize)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    const WORKER_QUEUE_LEN: usize = 1024;
+
+    let workers = workers.max(1);
+    let mut queues = Vec::with_capacity(workers);
+    let mut handles = Vec::with_capacity(workers);
+    for _ in 0..workers {
+        let (sender, mut receiver) = tokio::sync::mpsc::channel::<Transaction>(WORKER_QUEUE_LEN);
+        let ledger = ledger.clone();
+        handles.push(tokio::task::spawn(async move {
+            while let Some(transaction) = receiver.recv().await {
+                let tx = transaction.get_transaction_id();
+                let client = transaction.get_client_id();
+                if let Err(e) = ledger.process_transaction(transaction) {
+                    warn!(client, tx, "Error processing transaction: {e}");
+                }
+            }
+        }));
+        queues.push(sender);
+    }
+
+    let mut transaction_stream =
+        create_numbered_transaction_stream(reader, StreamConfig::default()).await;
+    while let Some((line, transaction_result)) = transaction_stream.next().await {
+        match transaction_result {
+            Ok(transaction) => {
+                let worker = transaction.get_client_id() as usize % workers;
+                if queues[worker].send(transaction).await.is_err() {
+                    warn!(worker, "Worker stopped, transaction dropped");
+                }
+            }
+            Err(e) => warn!(line, ?e, "Error in transaction stream"),
+        }
+    }
+    // closing the queues lets the workers finish once they are drained
+    drop(queues);
+    for handle in handles {
+        if let Err(e) = handle.await {
+            warn!("Join error: {e}");
+        }
+    }
+}
+
+/// Parses the input on a separate task running up to `buffer` transactions ahead of the
+/// processing, so parsing and processing overlap. Transactions are still processed one at
+/// a time in input order, so every client sees its transactions in order.
+/// Records that can't be parsed are logged and skipped.
+pub async fn run_buffered<R>(reader: R, ledger: Arc<Ledger>, buffer: usize)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let (sender, mut receiver) = tokio::sync::mpsc::channel::<Transaction>(buffer.max(1));
+    let parser = tokio::task::spawn(async move {
+        let mut transaction_stream =
+            create_numbered_transaction_stream(reader, StreamConfig::default()).await;
+        while let Some((line, transaction_result)) = transaction_stream.next().await {
+            match transaction_result {
+                Ok(transaction) => {
+                    if sender.send(transaction).await.is_err() {
+                        break;
+                    }
+                }
+                Err(e) => warn!(line, ?e, "Error in transaction stream"),
+            }
+        }
+    });
+
+    while let Some(transaction) = receiver.recv().await {
+        let tx = transaction.get_transaction_id();
+        let client = transaction.get_client_id();
+        if let Err(e) = ledger.process_transaction(transaction) {
+            warn!(client, tx, "Error processing transaction: {e}");
+        }
+    }
+    if let Err(e) = parser.await {
+        warn!("Join error: {e}");
+    }
+}
+
+/// Processes the `readers` in sequence as one logical stream. Only the first reader needs
+/// the header, repeated headers in the following readers are skipped.
+pub async fn run_chained<R>(readers: Vec<R>, ledger: Arc<Ledger>)
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let mut readers = readers.into_iter();
+    let Some(first) = readers.next() else {
+        return;
+    };
+    let chained = readers.fold(
+        Box::new(first) as Box<dyn AsyncRead + Unpin + Send>,
+        // a reader might not end with a newline
+        |chained, reader| Box::new(chained.chain(&b"\n"[..]).chain(reader)),
+    );
+    run(chained, ledger).await
+}
+
+/// Like `run`, but yields each transaction's outcome as it gets processed.
+/// Transactions are only processed while the returned stream is polled.
+/// Records that can't be parsed are logged and skipped.
+pub async fn run_streaming<R>(
+    reader: R,
+    ledger: Arc<Ledger>,
+) -> impl futures::Stream<Item = (Transaction, TransactionOutcome)>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    create_numbered_transaction_stream(reader, StreamConfig::default())
+        .await
+        .filter_map(move |(line, transaction_result)| {
+            let item = match transaction_result {
+                Ok(transaction) => {
+                    let outcome = ledger.process_transaction(transaction.clone()).into();
+                    Some((transaction, outcome))
+                }
+                Err(e) => {
+                    warn!(line, ?e, "Error in transaction stream");
+                    None
+                }
+            };
+            futures::future::ready(item)
+        })
+}
+
+/// Computes what processing `reader` would change, without mutating `ledger`.
+/// The input is applied to a `Ledger::scratch_copy`, not firing the apply hook.
+pub async fn dry_run_diff<R>(reader: R, ledger: &Ledger) -> Vec<ClientDelta>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let before = ledger.snapshot();
+    let scratch = Arc::new(ledger.scratch_copy());
+    run(reader, scratch.clone()).await;
+    before.delta(&scratch.snapshot())
+}
+
+/// A record of the input that can't be parsed into a transaction
+#[derive(Debug, PartialEq, Clone)]
+pub struct InvalidRecord {
+    pub line: u64,
+    pub reason: String,
+}
+
+/// What processing an input would run into, as found by `validate`
+#[derive(Default, Debug, PartialEq, Clone)]
+pub struct ValidationReport {
+    /// Transactions parsed, whether they'd be applied or not
+    pub transactions: usize,
+    pub invalid_records: Vec<InvalidRecord>,
+    /// Transactions the ledger would reject, in input order
+    pub rejections: Vec<Rejection>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.invalid_records.is_empty() && self.rejections.is_empty()
+    }
+}
+
+/// Checks the whole input as if it was processed into an empty ledger, without touching
+/// any real one: every record is parsed and applied to a scratch ledger.
+pub async fn validate<R>(reader: R) -> ValidationReport
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    validate_against(reader, &Ledger::new()).await
+}
+
+/// Like `validate`, simulating the input on a `Ledger::scratch_copy` of `ledger`
+pub async fn validate_against<R>(reader: R, ledger: &Ledger) -> ValidationReport
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let scratch = ledger.scratch_copy();
+    let mut report = ValidationReport::default();
+    let mut transaction_stream =
+        create_numbered_transaction_stream(reader, StreamConfig::default()).await;
+    while let Some((line, transaction_result)) = transaction_stream.next().await {
+        match transaction_result {
+            Ok(transaction) => {
+                report.transactions += 1;
+                let (client, tx) = (
+                    transaction.get_client_id(),
+                    transaction.get_transaction_id(),
+                );
+                if let Err(e) = scratch.process_transaction(transaction) {
+                    report.rejections.push(Rejection {
+                        line,
+                        client,
+                        tx,
+                        reason: e.into(),
+                    });
+                }
+            }
+            Err(e) => report.invalid_records.push(InvalidRecord {
+                line,
+                reason: e.to_string(),
+            }),
+        }
+    }
+    report
+}
+
+/// Runs the same input into a primary and a shadow `Ledger`, e.g. to verify a storage
+/// backend against the current one before migrating.
+pub struct DualRunner<P: WalletStore, S: WalletStore> {
+    pub primary: Ledger<P>,
+    pub shadow: Ledger<S>,
+}
+
+impl<P: WalletStore, S: WalletStore> DualRunner<P, S> {
+    pub fn new(primary: Ledger<P>, shadow: Ledger<S>) -> Self {
+        Self { primary, shadow }
+    }
+
+    /// Applies every transaction of `reader` to both ledgers and returns `divergence()`
+    pub async fn run<R>(&self, reader: R) -> Vec<ClientDelta>
+    where
+        R: AsyncRead + Unpin + Send + 'static,
+    {
+        let mut transaction_stream =
+            create_numbered_transaction_stream(reader, StreamConfig::default()).await;
+        while let Some((line, transaction_result)) = transaction_stream.next().await {
+            match transaction_result {
+                Ok(transaction) => {
+                    let primary_result = self.primary.process_transaction(transaction.clone());
+                    let shadow_result = self.shadow.process_transaction(transaction);
+                    if primary_result.is_ok() != shadow_result.is_ok() {
+                        warn!(
+                            ?primary_result,
+                            ?shadow_result,
+                            "Ledgers disagree on outcome"
+                        );
+                    }
+                }
+                Err(e) => warn!(line, ?e, "Error in transaction stream"),
+            }
+        }
+        self.divergence()
+    }
+
+    /// Per-client differences of the shadow from the primary, empty when they agree
+    pub fn divergence(&self) -> Vec<ClientDelta> {
+        self.primary.snapshot().delta(&self.shadow.snapshot())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OrderingAnomalyKind {
+    ResolveBeforeDispute,
+    ChargebackBeforeDispute,
+    DisputeAfterChargeback,
+}
+
+/// A record that can't be valid given the records before it
+#[derive(Debug, PartialEq, Clone)]
+pub struct OrderingAnomaly {
+    pub line: u64,
+    pub client: ClientId,
+    pub tx: TransactionId,
+    pub kind: OrderingAnomalyKind,
+}
+
+/// Reports the records of `reader` that reference a dispute in an impossible order,
+/// without applying anything to a ledger. Records that can't be parsed are skipped.
+pub async fn analyze_ordering<R>(reader: R) -> Vec<OrderingAnomaly>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    #[derive(PartialEq)]
+    enum DisputeState {
+        Disputed,
+        Resolved,
+        Chargedback,
+    }
+
+    let mut anomalies = Vec::new();
+    let mut disputes = HashMap::new();
+    let mut transaction_stream =
+        create_numbered_transaction_stream(reader, StreamConfig::default()).await;
+    while let Some((line, transaction_result)) = transaction_stream.next().await {
+        let transaction = match transaction_result {
+            Ok(transaction) => transaction,
+            Err(e) => {
+                warn!(line, ?e, "Error in transaction stream");
+                continue;
+            }
+        };
+        let client = transaction.get_client_id();
+        let tx = transaction.get_transaction_id();
+        let state = disputes.get(&(client, tx));
+        let (kind, next_state) = match transaction {
+            Transaction::Deposit { .. }
+            | Transaction::Withdrawal { .. }
+            | Transaction::Freeze { .. }
+            | Transaction::Unfreeze { .. } => continue,
+            Transaction::Dispute { .. } | Transaction::PartialDispute { .. } => match state {
+                Some(DisputeState::Chargedback) => {
+                    (Some(OrderingAnomalyKind::DisputeAfterChargeback), None)
+                }
+                _ => (None, Some(DisputeState::Disputed)),
+            },
+            Transaction::Resolve { .. } => match state {
+                Some(DisputeState::Disputed) => (None, Some(DisputeState::Resolved)),
+                _ => (Some(OrderingAnomalyKind::ResolveBeforeDispute), None),
+            },
+            Transaction::Chargeback { .. } => match state {
+                Some(DisputeState::Disputed) => (None, Some(DisputeState::Chargedback)),
+                _ => (Some(OrderingAnomalyKind::ChargebackBeforeDispute), None),
+            },
+        };
+        if let Some(kind) = kind {
+            anomalies.push(OrderingAnomaly {
+                line,
+                client,
+                tx,
+                kind,
+            });
+        }
+        if let Some(next_state) = next_state {
+            disputes.insert((client, tx), next_state);
         }
     }
+    anomalies
 }
@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+/// A file in the temp dir, unique to this test process
+fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("payments-engine-{}-{name}", std::process::id()))
+}
+
+#[test]
+fn test_binary_writes_dump_to_output_file() {
+    let input = temp_path("transactions.csv");
+    let output = temp_path("accounts.csv");
+    std::fs::write(
+        &input,
+        "type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 2, 2\n",
+    )
+    .unwrap();
+    // Stale contents are truncated
+    std::fs::write(&output, "stale\n".repeat(10)).unwrap();
+
+    let run = Command::new(env!("CARGO_BIN_EXE_payments-engine"))
+        .arg(&input)
+        .arg("--output")
+        .arg(&output)
+        .output()
+        .unwrap();
+
+    assert!(
+        run.status.success(),
+        "{}",
+        String::from_utf8_lossy(&run.stderr)
+    );
+    assert!(run.stdout.is_empty());
+    assert_eq!(
+        std::fs::read_to_string(&output).unwrap(),
+        "client, available, held, total, locked\n1, 3, 0, 3, false\n"
+    );
+    let _ = std::fs::remove_file(input);
+    let _ = std::fs::remove_file(output);
+}
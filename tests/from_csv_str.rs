@@ -1,10 +1,27 @@
-use std::io::BufRead;
+use std::collections::HashSet;
+use std::io::{BufRead, Cursor};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use assert_str::assert_str_trim_eq;
+use futures::StreamExt;
+use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
-use payments_engine::domain::Ledger;
-use payments_engine::run_csv_stream::run;
+use parking_lot::Mutex;
+use payments_engine::csv::ClientIdMap;
+use payments_engine::domain::{
+    namespaced_client_id, ClientChange, ClientId, DepositLogError, EngineConfig, InMemoryStore,
+    Ledger, LedgerError, RejectionKind, Transaction, TransactionType, Wallet, WalletError,
+    WalletStore,
+};
+use payments_engine::run_csv_stream::{
+    analyze_ordering, dry_run_diff, open_input, run, run_buffered, run_chained, run_collecting,
+    run_many, run_parallel, run_streaming, run_sync, run_with_config, try_run, try_run_with_config,
+    validate, validate_against, DualRunner, OrderingAnomaly, OrderingAnomalyKind, Rejection,
+    RejectionReason, RunConfig, RunError, RunReport, TransactionOutcome,
+};
 
 #[tokio::test]
 async fn test_deposit_and_withdraw_work() {
@@ -164,6 +181,159 @@ client, available, held, total, locked
     assert_str_trim_eq!(expected, output);
 }
 
+#[tokio::test]
+async fn test_deposit_and_dispute_after_chargeback_are_rejected() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 1, 2, 10
+dispute, 1, 1
+chargeback, 1, 1
+deposit, 1, 3, 20
+dispute, 1, 2
+";
+    let expected = "
+client, available, held, total, locked
+1, 10, 0, 10, true
+";
+    let output = get_sorted_ledger_dump(test_data).await;
+
+    assert_str_trim_eq!(expected, output);
+}
+
+#[tokio::test]
+async fn test_freeze_locks_account_and_keeps_balances_until_unfreeze() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 4
+freeze, 1, 3
+withdrawal, 1, 4, 5
+deposit, 1, 5, 1
+freeze, 2, 6
+unfreeze, 2, 7
+withdrawal, 2, 8, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 10, 0, 10, true
+2, 3, 0, 3, false
+";
+    let output = get_sorted_ledger_dump(test_data).await;
+
+    assert_str_trim_eq!(expected, output);
+}
+
+#[tokio::test]
+async fn test_unfreeze_doesnt_lift_a_chargeback_lock() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 1
+chargeback, 1, 1
+unfreeze, 1, 3
+withdrawal, 1, 4, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 5, 0, 5, true
+";
+    let output = get_sorted_ledger_dump(test_data).await;
+
+    assert_str_trim_eq!(expected, output);
+}
+
+#[tokio::test]
+async fn test_dispute_amount_is_ignored_by_default_even_out_of_range() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 4
+dispute, 1, 1, 1.23456
+dispute, 2, 2, -1
+";
+    let expected = "
+client, available, held, total, locked
+1, 0, 10, 10, false
+2, 0, 4, 4, false
+";
+    let output = get_sorted_ledger_dump(test_data).await;
+    assert_str_trim_eq!(expected, output);
+
+    let ledger = Arc::new(Ledger::with_config(EngineConfig {
+        partial_disputes: true,
+        ..Default::default()
+    }));
+    let report = run_collecting(test_data.as_bytes(), ledger.clone()).await;
+    assert_eq!(report.applied, 3);
+    assert_eq!(
+        report.rejections[0].reason,
+        RejectionReason::Wallet(WalletError::DepositLogError(
+            DepositLogError::InvalidDisputedAmount
+        ))
+    );
+}
+
+#[tokio::test]
+async fn test_negative_deposit_is_rejected_without_using_its_tx_id() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 20
+deposit, 1, 2, -50
+withdrawal, 1, 3, 0
+deposit, 1, 2, 5
+";
+    let expected = "
+client, available, held, total, locked
+1, 25, 0, 25, false
+";
+    let output = get_sorted_ledger_dump(test_data).await;
+
+    assert_str_trim_eq!(expected, output);
+}
+
+#[tokio::test]
+async fn test_chargeback_after_resolve_is_rejected() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+dispute, 1, 1
+resolve, 1, 1
+chargeback, 1, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 5, 0, 5, false
+";
+    let output = get_sorted_ledger_dump(test_data).await;
+
+    assert_str_trim_eq!(expected, output);
+}
+
+#[tokio::test]
+async fn test_chargeback_after_resolve_requires_redispute() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 1, 2, 3
+dispute, 1, 1
+resolve, 1, 1
+chargeback, 1, 1
+dispute, 1, 1
+chargeback, 1, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 3, 0, 3, true
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_with_config(test_data.as_bytes(), ledger.clone(), RunConfig::default()).await;
+
+    assert_eq!(report.rejected, 1);
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+}
+
 #[tokio::test]
 async fn test_decimals_up_to_4_places_are_accepted() {
     let test_data = "
@@ -180,16 +350,935 @@ client, available, held, total, locked
     assert_str_trim_eq!(expected, output);
 }
 
-async fn get_sorted_ledger_dump(test_data: &'static str) -> String {
+#[tokio::test]
+async fn test_results_are_the_same_with_and_without_spawning_tasks() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 2, 2, 10
+withdrawal, 1, 3, 2
+dispute, 2, 2
+deposit, 3, 4, 1.5
+chargeback, 2, 2
+resolve, 1, 1
+";
+    let config = RunConfig {
+        spawn_tasks: true,
+        ..Default::default()
+    };
+    let spawned = get_sorted_ledger_dump_with_config(test_data, config).await;
+    let not_spawned = get_sorted_ledger_dump_with_config(test_data, RunConfig::default()).await;
+
+    assert_str_trim_eq!(spawned, not_spawned);
+}
+
+#[tokio::test]
+async fn test_chained_readers_are_processed_as_one_stream() {
+    let first = "
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5";
+    let second = "type, client, tx, amount
+withdrawal, 1, 3, 4
+dispute, 2, 2
+";
+    let third = "deposit, 3, 4, 1";
+    let expected = "
+client, available, held, total, locked
+1, 6, 0, 6, false
+2, 0, 5, 5, false
+3, 1, 0, 1, false
+";
+    let ledger = Arc::new(Ledger::new());
+    run_chained(
+        vec![first.as_bytes(), second.as_bytes(), third.as_bytes()],
+        ledger.clone(),
+    )
+    .await;
+
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+}
+
+#[tokio::test]
+async fn test_run_streaming_yields_each_outcome_in_order() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 20
+invalid, 1, 3, 1
+dispute, 1, 1
+dispute, 2, 1
+";
+    let ledger = Arc::new(Ledger::new());
+    let outcomes: Vec<_> = run_streaming(test_data.as_bytes(), ledger)
+        .await
+        .collect()
+        .await;
+
+    assert_eq!(outcomes.len(), 4);
+    assert!(matches!(
+        outcomes[0],
+        (
+            Transaction::Deposit { tx: 1, .. },
+            TransactionOutcome::Applied
+        )
+    ));
+    assert!(matches!(
+        outcomes[1],
+        (
+            Transaction::Withdrawal { tx: 2, .. },
+            TransactionOutcome::Rejected(LedgerError::WalletError(WalletError::InsufficientFunds))
+        )
+    ));
+    assert!(matches!(
+        outcomes[2],
+        (
+            Transaction::Dispute { client: 1, tx: 1 },
+            TransactionOutcome::Applied
+        )
+    ));
+    assert!(matches!(
+        outcomes[3],
+        (
+            Transaction::Dispute { client: 2, tx: 1 },
+            TransactionOutcome::Rejected(LedgerError::InexistentClient(2))
+        )
+    ));
+}
+
+#[tokio::test]
+async fn test_identical_consecutive_transactions_are_skipped_when_enabled() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+dispute, 1, 1
+dispute, 1, 1
+withdrawal, 1, 3, 2
+withdrawal, 1, 3, 2
+";
+    let expected = "
+client, available, held, total, locked
+1, 3, 10, 13, false
+";
+    let ledger = Arc::new(Ledger::new());
+    let config = RunConfig {
+        skip_consecutive_duplicates: true,
+        ..Default::default()
+    };
+    let report = run_with_config(test_data.as_bytes(), ledger.clone(), config).await;
+
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+    let expected_report = RunReport {
+        applied: 4,
+        skipped_duplicate: 2,
+        ..Default::default()
+    };
+    assert_eq!(report, expected_report);
+}
+
+#[tokio::test]
+async fn test_dry_run_diff_predicts_changes_without_mutating_the_ledger() {
+    let initial_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 5
+";
+    let batch = "
+type, client, tx, amount
+withdrawal, 1, 3, 4
+dispute, 2, 2
+chargeback, 2, 2
+deposit, 3, 4, 1
+";
+    let ledger = Arc::new(Ledger::new());
+    run(initial_data.as_bytes(), ledger.clone()).await;
+    let before = ledger.snapshot();
+
+    let predicted = dry_run_diff(batch.as_bytes(), &ledger).await;
+    assert_eq!(ledger.snapshot(), before);
+
+    run(batch.as_bytes(), ledger.clone()).await;
+    let actual = before.delta(&ledger.snapshot());
+    assert_eq!(predicted, actual);
+    assert_eq!(predicted.len(), 3);
+}
+
+#[tokio::test]
+async fn test_delta_omits_clients_whose_balances_and_lock_are_unchanged() {
+    let ledger = Arc::new(Ledger::new());
+    run(
+        "type, client, tx, amount\ndeposit, 1, 1, 10\ndeposit, 2, 2, 5\n".as_bytes(),
+        ledger.clone(),
+    )
+    .await;
+    let before = ledger.snapshot();
+
+    let batch = "
+type, client, tx, amount
+dispute, 1, 1
+resolve, 1, 1
+withdrawal, 2, 3, 2
+";
+    run(batch.as_bytes(), ledger.clone()).await;
+    let delta = before.delta(&ledger.snapshot());
+
+    assert_ne!(ledger.snapshot(), before);
+    assert_eq!(
+        delta
+            .iter()
+            .map(|delta| (delta.client, delta.change))
+            .collect::<Vec<_>>(),
+        [(2, ClientChange::Updated)]
+    );
+}
+
+#[tokio::test]
+async fn test_validate_reports_problems_per_line_without_touching_the_ledger() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, two, 5
+withdrawal, 1, 3, 15
+dispute, 1, 1
+";
     let ledger = Arc::new(Ledger::new());
+    let report = validate(test_data.as_bytes()).await;
+
+    assert!(!report.is_valid());
+    assert_eq!(report.transactions, 3);
+    assert_eq!(report.invalid_records.len(), 1);
+    assert_eq!(report.invalid_records[0].line, 3);
+    assert_eq!(
+        report.rejections,
+        [Rejection {
+            line: 4,
+            client: 1,
+            tx: 3,
+            reason: RejectionReason::Wallet(WalletError::InsufficientFunds),
+        }]
+    );
+
     run(test_data.as_bytes(), ledger.clone()).await;
+    let before = ledger.snapshot();
+    let report = validate_against(
+        "type, client, tx, amount\nresolve, 1, 1\n".as_bytes(),
+        &ledger,
+    )
+    .await;
+    assert!(report.is_valid());
+    assert_eq!(ledger.snapshot(), before);
+}
+
+#[tokio::test]
+async fn test_validate_against_and_dry_run_dont_fire_the_apply_hook() {
+    let fired = Arc::new(Mutex::new(0));
+    let counter = fired.clone();
+    let ledger = Arc::new(Ledger::new().with_apply_hook(move |_, _| *counter.lock() += 1));
+    run(
+        "type, client, tx, amount\ndeposit, 1, 1, 10\n".as_bytes(),
+        ledger.clone(),
+    )
+    .await;
+    let test_data = "type, client, tx, amount\ndeposit, 1, 2, 5\ndispute, 1, 1\n";
+
+    let report = validate_against(test_data.as_bytes(), &ledger).await;
+    assert!(report.is_valid());
+    assert_eq!(dry_run_diff(test_data.as_bytes(), &ledger).await.len(), 1);
+
+    assert_eq!(*fired.lock(), 1);
+}
+
+/// Collects the logs of the current thread while alive
+struct CapturedLogs {
+    output: Arc<Mutex<Vec<u8>>>,
+    _guard: tracing::subscriber::DefaultGuard,
+}
+
+impl CapturedLogs {
+    fn start() -> Self {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_max_level(tracing::Level::WARN)
+            .with_writer(move || LogWriter(writer.clone()))
+            .finish();
+        Self {
+            output,
+            _guard: tracing::subscriber::set_default(subscriber),
+        }
+    }
+
+    fn lines(&self) -> Vec<String> {
+        String::from_utf8(self.output.lock().clone())
+            .unwrap()
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+struct LogWriter(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn test_malformed_record_warning_reports_its_line() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 1, 2, 5
+deposit, 1, x, 5
+withdrawal, 1, 3, 50
+";
+    let logs = CapturedLogs::start();
+    run(test_data.as_bytes(), Arc::new(Ledger::new())).await;
+
+    let lines = logs.lines();
+    let parse_error = lines
+        .iter()
+        .find(|l| l.contains("Error in transaction stream"))
+        .unwrap();
+    assert!(parse_error.contains("line=4"), "{parse_error}");
+    let rejection = lines
+        .iter()
+        .find(|l| l.contains("Error processing transaction"))
+        .unwrap();
+    assert!(rejection.contains("line=5"), "{rejection}");
+}
+
+#[tokio::test]
+async fn test_resolve_of_never_disputed_deposit_leaves_balances_untouched() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 4
+resolve, 1, 1
+chargeback, 1, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 6, 0, 6, false
+";
+    let logs = CapturedLogs::start();
+    let ledger = Arc::new(Ledger::new());
+    let report = run_collecting(test_data.as_bytes(), ledger.clone()).await;
+
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+    assert_eq!(
+        report
+            .rejections
+            .iter()
+            .map(|r| (r.line, r.reason.clone()))
+            .collect::<Vec<_>>(),
+        [
+            (
+                4,
+                RejectionReason::Wallet(WalletError::DepositLogError(
+                    DepositLogError::CantResolveUndisputed
+                ))
+            ),
+            (
+                5,
+                RejectionReason::Wallet(WalletError::DepositLogError(
+                    DepositLogError::CantChargebackUndisputed
+                ))
+            ),
+        ]
+    );
+    let lines = logs.lines();
+    assert!(
+        lines
+            .iter()
+            .any(|l| l.contains("line=4") && l.contains("Can't resolve undisputed deposit")),
+        "{lines:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_run_error_when_input_file_cant_be_opened() {
+    let result = open_input("does/not/exist.csv").await;
+
+    assert!(
+        matches!(result, Err(RunError::OpenInput { path, .. }) if path == "does/not/exist.csv")
+    );
+}
+
+/// Fails every read, to simulate a disk error
+struct FailingReader;
+
+impl AsyncRead for FailingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        _buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Err(std::io::Error::other("disk error")))
+    }
+}
+
+#[tokio::test]
+async fn test_run_error_when_reading_input_fails_midway() {
+    let test_data = "type, client, tx, amount\ndeposit, 1, 1, 5\n";
+    let reader = test_data.as_bytes().chain(FailingReader);
+    let ledger = Arc::new(Ledger::new());
+
+    let result = try_run_with_config(reader, ledger.clone(), RunConfig::default()).await;
+
+    assert!(matches!(result, Err(RunError::Io(_))));
+    assert_eq!(ledger.health().clients, 1);
+}
+
+#[tokio::test]
+async fn test_try_run_only_fails_on_the_stream_not_on_rejected_records() {
+    let test_data =
+        "type, client, tx, amount\ndeposit, 1, 1, 5\nwithdrawal, 1, 2, 10\nresolve, 2, 1\n";
+    let ledger = Arc::new(Ledger::new());
+
+    let report = try_run(test_data.as_bytes(), ledger.clone()).await.unwrap();
+    assert_eq!((report.applied, report.rejected), (1, 2));
+
+    let reader = test_data.as_bytes().chain(FailingReader);
+    let result = try_run(reader, Arc::new(Ledger::new())).await;
+    assert!(matches!(result, Err(RunError::Io(_))));
+}
+
+#[tokio::test]
+async fn test_run_error_on_invalid_utf8_when_strict() {
+    let test_data: &[u8] = b"type, client, tx, amount\ndeposit, 1, 1, 5\ndeposit, 1, 2, \xff\n";
+    let config = RunConfig {
+        strict: true,
+        ..Default::default()
+    };
+
+    let result = try_run_with_config(test_data, Arc::new(Ledger::new()), config).await;
+
+    assert!(matches!(result, Err(RunError::Utf8 { line: 3 })));
+}
+
+#[tokio::test]
+async fn test_run_error_on_first_rejected_transaction_when_strict() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 5
+withdrawal, 1, 2, 10
+deposit, 1, 3, 5
+";
+    let config = RunConfig {
+        strict: true,
+        ..Default::default()
+    };
+    let ledger = Arc::new(Ledger::new());
+
+    let result = try_run_with_config(test_data.as_bytes(), ledger.clone(), config).await;
+
+    assert!(matches!(result, Err(RunError::StrictAbort { line: 3, .. })));
+    assert_eq!(ledger.snapshot().clients[&1].total.to_string(), "5");
+    // without `strict` every record is processed
+    let report = try_run_with_config(
+        test_data.as_bytes(),
+        Arc::new(Ledger::new()),
+        RunConfig::default(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(report.applied, 2);
+    assert_eq!(report.rejected, 1);
+}
+
+#[tokio::test]
+async fn test_run_error_when_input_isnt_processed_in_time() {
+    // the writer is kept alive, so the input never ends
+    let (_writer, reader) = tokio::io::duplex(64);
+    let config = RunConfig {
+        timeout: Some(Duration::from_millis(10)),
+        ..Default::default()
+    };
+
+    let result = try_run_with_config(reader, Arc::new(Ledger::new()), config).await;
+
+    assert!(matches!(result, Err(RunError::Timeout(_))));
+}
+
+#[tokio::test]
+async fn test_run_error_when_error_threshold_is_exceeded() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 5
+withdrawal, 2, 2, 1
+dispute, 1, 99
+deposit, 1, 3, 5
+";
+    let config = RunConfig {
+        max_errors: Some(1),
+        ..Default::default()
+    };
+    let ledger = Arc::new(Ledger::new());
+
+    let result = try_run_with_config(test_data.as_bytes(), ledger.clone(), config).await;
+
+    assert!(matches!(result, Err(RunError::ErrorThresholdExceeded(1))));
+    assert_eq!(ledger.snapshot().clients[&1].total.to_string(), "5");
+}
+
+#[tokio::test]
+async fn test_namespaced_tenants_keep_their_clients_separate() {
+    let tenant_a = "
+type, client, tx, amount
+deposit, 1, 1, 5
+";
+    let tenant_b = "
+type, client, tx, amount
+deposit, 1, 1, 7
+withdrawal, 1, 2, 2
+";
+    let ledger = Arc::new(Ledger::new());
+    for (namespace, test_data) in [(1, tenant_a), (2, tenant_b)] {
+        let mut config = RunConfig::default();
+        config.stream.client_namespace = Some(namespace);
+        run_with_config(test_data.as_bytes(), ledger.clone(), config).await;
+    }
+
+    let clients = ledger.snapshot().clients;
+    assert_eq!(clients.len(), 2);
+    assert_eq!(clients[&namespaced_client_id(1, 1)].total.to_string(), "5");
+    assert_eq!(clients[&namespaced_client_id(2, 1)].total.to_string(), "5");
+    assert_ne!(namespaced_client_id(1, 1), namespaced_client_id(2, 1));
+    assert_eq!(namespaced_client_id(0, 1), 1);
+}
+
+#[tokio::test]
+async fn test_type_filter_ignores_other_transaction_types() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 1, 2, 10
+withdrawal, 1, 3, 3
+dispute, 1, 1
+chargeback, 1, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 12, 0, 12, false
+";
+    let ledger = Arc::new(Ledger::with_config(EngineConfig {
+        type_filter: Some(HashSet::from([
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+        ])),
+        ..Default::default()
+    }));
+    run(test_data.as_bytes(), ledger.clone()).await;
+
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+}
+
+#[tokio::test]
+async fn test_resolve_of_unknown_tx_is_reported_apart_from_unknown_client() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 10
+resolve, 1, 99
+resolve, 2, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 10, 0, 10, false
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_with_config(test_data.as_bytes(), ledger.clone(), RunConfig::default()).await;
+
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+    assert_eq!(report.rejected, 2);
+    assert_eq!(
+        report.rejections,
+        [
+            Rejection {
+                line: 4,
+                client: 1,
+                tx: 99,
+                reason: RejectionReason::Wallet(WalletError::InexistentTransaction),
+            },
+            Rejection {
+                line: 5,
+                client: 2,
+                tx: 1,
+                reason: RejectionReason::InexistentClient,
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_run_collecting_reports_exactly_the_failed_transactions() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 15
+deposit, 2, 3, 5
+dispute, 2, 42
+withdrawal, 1, 4, 10
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_collecting(test_data.as_bytes(), ledger).await;
+
+    assert_eq!(report.applied, 3);
+    assert_eq!(report.rejected, 2);
+    assert_eq!(
+        report.rejections,
+        [
+            Rejection {
+                line: 3,
+                client: 1,
+                tx: 2,
+                reason: RejectionReason::Wallet(WalletError::InsufficientFunds),
+            },
+            Rejection {
+                line: 5,
+                client: 2,
+                tx: 42,
+                reason: RejectionReason::Wallet(WalletError::InexistentTransaction),
+            },
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_run_report_summarizes_counts_by_rejection_kind() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+withdrawal, 1, 2, 15
+deposit, 2, 3, 5
+dispute, 2, 42
+withdrawal, 1, 4, 20
+deposit, 1, nope, 1
+resolve, 3, 1
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_collecting(test_data.as_bytes(), ledger).await;
+
+    assert_eq!(report.read(), 7);
+    assert_eq!(
+        report.rejected_by_kind(),
+        [
+            (RejectionKind::InsufficientFunds, 2),
+            (RejectionKind::InexistentTransaction, 1),
+            (RejectionKind::InexistentClient, 1),
+        ]
+    );
+    assert_eq!(
+        report.to_string(),
+        "read 7 records: 2 applied, 4 rejected (insufficient_funds: 2, \
+         inexistent_transaction: 1, inexistent_client: 1), 1 parse errors, \
+         0 skipped duplicates"
+    );
+}
+
+#[tokio::test]
+async fn test_run_report_and_metrics_agree_on_tx_id_rejections() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 1, 5
+deposit, 2, 2, 5
+dispute, 2, 1
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_collecting(test_data.as_bytes(), ledger.clone()).await;
+
+    assert_eq!(
+        report.rejections[1].reason,
+        RejectionReason::ClientMismatch { expected: 1 }
+    );
+    let by_kind = report.rejected_by_kind();
+    assert_eq!(
+        by_kind,
+        [
+            (RejectionKind::DuplicateTransactionId, 1),
+            (RejectionKind::ClientMismatch, 1),
+        ]
+    );
+    for (kind, count) in by_kind {
+        assert_eq!(ledger.metrics().rejections(kind), count as u64);
+    }
+}
+
+#[tokio::test]
+async fn test_client_id_map_merges_clients_mapped_to_the_same_id() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 2, 2, 10
+withdrawal, 1, 3, 12
+deposit, 3, 4, 1
+";
+    let expected = "
+client, available, held, total, locked
+3, 1, 0, 1, false
+5, 3, 0, 3, false
+";
+    let mut config = RunConfig::default();
+    config.stream.client_id_map = Some(ClientIdMap::new(|client| match client {
+        1 | 2 => 5,
+        client => client,
+    }));
+    let output = get_sorted_ledger_dump_with_config(test_data, config).await;
+
+    assert_str_trim_eq!(expected, output);
+}
+
+/// Buggy store mixing up the clients with the same parity
+#[derive(Default, Debug)]
+struct ParityStore(InMemoryStore);
+
+impl WalletStore for ParityStore {
+    type Guard<'a> = <InMemoryStore as WalletStore>::Guard<'a>;
+
+    fn get(&self, client: &ClientId) -> Option<Self::Guard<'_>> {
+        self.0.get(&(client % 2))
+    }
+
+    fn get_or_create<F>(&self, client: &ClientId, create: F) -> Self::Guard<'_>
+    where
+        F: FnOnce() -> Wallet,
+    {
+        self.0.get_or_create(&(client % 2), create)
+    }
+
+    fn for_each<F>(&self, f: F)
+    where
+        F: FnMut(ClientId, &Mutex<Wallet>),
+    {
+        self.0.for_each(f)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+#[tokio::test]
+async fn test_dual_runner_reports_divergence_of_the_shadow_ledger() {
+    let test_data = "
+type, client, tx, amount
+deposit, 1, 1, 5
+deposit, 2, 2, 10
+deposit, 3, 3, 1
+dispute, 2, 2
+";
+    let runner = DualRunner::new(Ledger::new(), Ledger::new());
+    assert!(runner.run(test_data.as_bytes()).await.is_empty());
+
+    let runner = DualRunner::new(
+        Ledger::new(),
+        Ledger::with_store(ParityStore::default(), EngineConfig::default()),
+    );
+    let divergence = runner.run(test_data.as_bytes()).await;
+
+    let diverged: Vec<_> = divergence
+        .iter()
+        .map(|delta| (delta.client, delta.change))
+        .collect();
+    assert_eq!(
+        diverged,
+        [
+            (0, ClientChange::Created),
+            (1, ClientChange::Updated),
+            (2, ClientChange::Removed),
+            (3, ClientChange::Removed)
+        ]
+    );
+}
+
+#[test]
+fn test_run_sync_processes_input_without_a_runtime() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 3
+withdrawal, 1, 3, 4.5
+dispute, 2, 2
+";
+    let expected = "
+client, available, held, total, locked
+1, 5.5, 0, 5.5, false
+2, 0, 3, 3, false
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_sync(test_data.as_bytes(), ledger.clone()).unwrap();
+
+    assert_eq!(report.applied, 4);
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+}
+
+#[tokio::test]
+async fn test_run_many_replays_inputs_in_order_into_one_ledger() {
+    let day1 = "type, client, tx, amount
+deposit, 1, 1, 10
+deposit, 2, 2, 3
+";
+    let day2 = "type, client, tx, amount
+withdrawal, 1, 3, 4
+dispute, 2, 2
+withdrawal, 3, 4, 1
+";
+    let expected = "
+client, available, held, total, locked
+1, 6, 0, 6, false
+2, 0, 3, 3, false
+";
+    let ledger = Arc::new(Ledger::new());
+    let report = run_many([day1.as_bytes(), day2.as_bytes()], ledger.clone())
+        .await
+        .unwrap();
+
+    assert_eq!(report.applied, 4);
+    assert_eq!(report.rejected, 1);
+    assert_eq!(report.rejections[0].line, 4);
+    assert_str_trim_eq!(expected, sort_ledger_dump(&ledger));
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_run_buffered_matches_serial_run() {
+    let mut test_data = String::from("type, client, tx, amount\n");
+    for tx in 1..=2000u32 {
+        let client = tx % 7;
+        let line = match tx % 5 {
+            0 => format!("withdrawal, {client}, {tx}, 3\n"),
+            1 => format!("dispute, {client}, {}\n", tx - 1),
+            2 => format!("resolve, {client}, {}\n", tx - 2),
+            3 => format!("chargeback, {client}, {}\n", tx - 3),
+            _ => format!("deposit, {client}, {tx}, {}.5\n", tx % 11),
+        };
+        test_data.push_str(&line);
+    }
+    test_data.push_str("deposit, 1, oops, 1\n");
+
+    let serial = Arc::new(Ledger::new());
+    run(Cursor::new(test_data.clone()), serial.clone()).await;
+    for buffer in [1, 16, 4096] {
+        let buffered = Arc::new(Ledger::new());
+        run_buffered(Cursor::new(test_data.clone()), buffered.clone(), buffer).await;
+
+        assert_eq!(*buffered, *serial, "buffer {buffer}");
+        assert_eq!(sort_ledger_dump(&buffered), sort_ledger_dump(&serial));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_run_parallel_gives_a_tx_id_reused_across_clients_to_exactly_one() {
+    let test_data = "type, client, tx, amount\ndeposit, 1, 7, 3\ndeposit, 2, 7, 5\n";
+
+    for _ in 0..20 {
+        let ledger = Arc::new(Ledger::new());
+        run_parallel(Cursor::new(test_data), ledger.clone(), 2).await;
+
+        // which client wins depends on the scheduling of the workers
+        let totals: Vec<_> = ledger
+            .iter_snapshots()
+            .into_iter()
+            .map(|(client, snapshot)| (client, snapshot.total.to_string()))
+            .collect();
+        assert!(
+            totals == [(1, "3".to_string())] || totals == [(2, "5".to_string())],
+            "{totals:?}"
+        );
+        assert_eq!(
+            ledger
+                .metrics()
+                .rejections(RejectionKind::DuplicateTransactionId),
+            1
+        );
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn test_run_parallel_matches_serial_run_for_many_clients() {
+    const CLIENTS: u32 = 500;
+    let mut test_data = String::from("type, client, tx, amount\n");
+    let mut tx = 0;
+    let mut next_tx = || {
+        tx += 1;
+        tx
+    };
+    for round in 0..4 {
+        for client in 1..=CLIENTS {
+            let deposit = next_tx();
+            test_data += &format!("deposit, {client}, {deposit}, {}.5\n", client % 7 + round);
+            // only succeeds if processed after the client's earlier deposits
+            test_data += &format!("withdrawal, {client}, {}, {}\n", next_tx(), round * 2);
+            if client % 5 == round {
+                test_data += &format!("dispute, {client}, {deposit}\n");
+            }
+            if client % 11 == round {
+                test_data +=
+                    &format!("dispute, {client}, {deposit}\nchargeback, {client}, {deposit}\n");
+            }
+        }
+    }
+
+    let serial = Arc::new(Ledger::new());
+    run(Cursor::new(test_data.clone()), serial.clone()).await;
+    let parallel = Arc::new(Ledger::new());
+    run_parallel(Cursor::new(test_data), parallel.clone(), 8).await;
+
+    assert_eq!(parallel.health().clients, CLIENTS as usize);
+    assert_eq!(parallel.snapshot(), serial.snapshot());
+}
+
+#[tokio::test]
+async fn test_analyze_ordering_reports_chargeback_before_dispute() {
+    let test_data = "type, client, tx, amount
+deposit, 1, 1, 5
+chargeback, 1, 1
+dispute, 1, 1
+chargeback, 1, 1
+dispute, 1, 1
+resolve, 2, 7
+";
+
+    let anomalies = analyze_ordering(test_data.as_bytes()).await;
+
+    let anomaly = |line, client, tx, kind| OrderingAnomaly {
+        line,
+        client,
+        tx,
+        kind,
+    };
+    assert_eq!(
+        anomalies,
+        vec![
+            anomaly(3, 1, 1, OrderingAnomalyKind::ChargebackBeforeDispute),
+            anomaly(6, 1, 1, OrderingAnomalyKind::DisputeAfterChargeback),
+            anomaly(7, 2, 7, OrderingAnomalyKind::ResolveBeforeDispute),
+        ]
+    );
+}
+
+async fn get_sorted_ledger_dump(test_data: &'static str) -> String {
+    get_sorted_ledger_dump_with_config(test_data, RunConfig::default()).await
+}
+
+async fn get_sorted_ledger_dump_with_config(test_data: &'static str, config: RunConfig) -> String {
+    let ledger = Arc::new(Ledger::new());
+    run_with_config(test_data.as_bytes(), ledger.clone(), config).await;
+    sort_ledger_dump(&ledger)
+}
+
+fn sort_ledger_dump(ledger: &Ledger) -> String {
     let mut output = Vec::new();
     ledger.dump_to_writer(&mut output).unwrap();
 
     let mut output = output.lines();
     let mut header = output.next().unwrap().unwrap();
     let mut lines = output.map(|v| v.unwrap()).collect::<Vec<_>>();
-    lines.sort_by_key(|l| l.split(',').collect::<Vec<_>>()[0].parse::<u16>().unwrap());
+    lines.sort_by_key(|l| {
+        l.split(',').collect::<Vec<_>>()[0]
+            .parse::<ClientId>()
+            .unwrap()
+    });
     header.push('\n');
     lines.iter_mut().for_each(|l| l.push('\n'));
     header.extend(lines);